@@ -10,10 +10,12 @@ use indicatif::ProgressBar;
 
 
 //Rayon is Rust equivalent of openMP
-use rayon::prelude::ParallelString;
+use rayon::prelude::ParallelSlice;
+use rayon::slice::ParallelSliceMut;
 //use rayon::prelude::ParallelSliceMut;
 use rayon::iter::IndexedParallelIterator;
 use rayon::iter::IntoParallelRefIterator;
+use rayon::iter::IntoParallelIterator;
 use rayon::iter::ParallelIterator;
 use rayon;
 
@@ -130,27 +132,6 @@ const fn base2number_ry(c: u8) -> Option<u8> {
     }
 }
 
-/// Maps 4mer from utf-8 to 8 bit representation
-const fn kmer_to_16_bit_ray_ry<const N: usize>( mer:  & [u8]) -> Option<u32> {
-    let mut result: u32 = 0;
-    let mut i = 0;
-    loop {
-        if i >= N {
-            break;
-        }
-        let c = mer[i];
-        result = result << 1;
-        result |= match base2number_ry(c) {
-            Some(b) => b as u32,
-            None => {
-                return None;
-            }
-        };
-        i += 1;
-    }
-
-    Some(result)
-}
 
 
 fn subm_rymers(bin_rep: u32, N: usize) -> (u32, u32) {
@@ -182,7 +163,16 @@ fn quotient_ry_mers<const A: usize, const B: usize, const C: usize, const D: usi
 }
 
 
-fn find_rymers_bytes(contig: &[u8]) -> ([f32; 528],[f32; 256], [f32; 136],  [f32; 64],  [f32; 36], usize) {
+// RY 10,9,8,7,6 mer counts and the number of invalid 10 base windows
+type RymerCounts = ([u32; 528],[u32; 256], [u32; 136],  [u32; 64],  [u32; 36], usize);
+// RymerCounts as f64, so windows with ambiguous bases can add fractional counts (see add_ambiguous_rymers)
+type FracRymerCounts = ([f64; 528],[f64; 256], [f64; 136],  [f64; 64],  [f64; 36], usize);
+// quotiented RY 10,9,8,7 mer and RY 6 mer distributions, and the number of invalid 10 base windows
+type RymerDistrs = ([f32; 528],[f32; 256], [f32; 136],  [f32; 64],  [f32; 36], usize);
+
+// Counts RY 10,9,8,7,6 mers over every window of 10 bases. The shorter RY mers are taken from the start of each
+// window (this is how they have always been counted, so the last few short RY mers of a contig are not counted).
+fn rymer_counts(contig: impl Iterator<Item = u8>) -> RymerCounts {
     let mut sixmer_counts = [0u32; 36];
     let mut sevenmer_counts = [0u32; 64];
     let mut eightmer_counts = [0u32; 136];
@@ -191,57 +181,37 @@ fn find_rymers_bytes(contig: &[u8]) -> ([f32; 528],[f32; 256], [f32; 136],  [f32
 
     let mut invalid_count = 0;
 
-    let mut fmer = [0u8;10];
-
-    for i in 0..9 {
-        fmer[i+1] = contig[i];
-    }
+    let mut window = RollingKmer::new(1, 10);
 
-   for b in  contig[9..].iter() {
-       for i in 0..9 {
-           fmer[i] = fmer[i+1];
-       }
-       fmer[9] = *b;
-       match(kmer_to_16_bit_ray_ry::<10>(&fmer)) {
-            Some(f) => {
-                let class = RY10TBL[f as usize];
-                tenmer_counts[class as usize] += 1;
-            },
-            None => {
-                invalid_count += 1;
-            },
-       }
-       match(kmer_to_16_bit_ray_ry::<9>(&fmer)) {
-            Some(f) => {
-                let class = RY9TBL[f as usize];
-                ninemer_counts[class as usize] += 1;
-            },
-            None => (),
+    for (pos, b) in contig.enumerate() {
+        window.push(base2number_ry(b));
+        if pos < 9 {
+            continue;
         }
-        match(kmer_to_16_bit_ray_ry::<8>(&fmer)) {
-            Some(f) => {
-                let class = RY8TBL[f as usize];
-                eightmer_counts[class as usize] += 1;
-            },
-            None => (),
+        match window.prefix(10) {
+            Some(f) => tenmer_counts[RY10TBL[f as usize] as usize] += 1,
+            None => invalid_count += 1,
         }
-        match(kmer_to_16_bit_ray_ry::<7>(&fmer)) {
-            Some(f) => {
-                let class = RY7TBL[f as usize];
-                sevenmer_counts[class as usize] += 1;
-            }
-            None => (),
+        if let Some(f) = window.prefix(9) {
+            ninemer_counts[RY9TBL[f as usize] as usize] += 1;
         }
-        match(kmer_to_16_bit_ray_ry::<6>(&fmer)) {
-            Some(f) => {
-                let class = RY6TBL[f as usize];
-                sixmer_counts[class as usize] += 1;
-            }
-            None => (),
+        if let Some(f) = window.prefix(8) {
+            eightmer_counts[RY8TBL[f as usize] as usize] += 1;
+        }
+        if let Some(f) = window.prefix(7) {
+            sevenmer_counts[RY7TBL[f as usize] as usize] += 1;
         }
-   }
+        if let Some(f) = window.prefix(6) {
+            sixmer_counts[RY6TBL[f as usize] as usize] += 1;
+        }
+    }
 
+    (tenmer_counts, ninemer_counts, eightmer_counts, sevenmer_counts, sixmer_counts, invalid_count)
+}
 
+// norms: the RY 10,9,8,7,6 mer entries of a FeatureNormalization. The quotients are always taken on L1 normed frequencies.
+fn rymer_distrs_from_counts(counts: FracRymerCounts, norms: &[Normalization]) -> RymerDistrs {
+    let (tenmer_counts, ninemer_counts, eightmer_counts, sevenmer_counts, sixmer_counts, invalid_count) = counts;
 
    let out_10mer  = norm_vector(tenmer_counts);
    let out_9mer  = norm_vector(ninemer_counts);
@@ -249,99 +219,261 @@ fn find_rymers_bytes(contig: &[u8]) -> ([f32; 528],[f32; 256], [f32; 136],  [f32
    let out_7mer = norm_vector(sevenmer_counts);
    let out_6mer = norm_vector(sixmer_counts);
 
-   let q10mer = norm_vector_f(quotient_ry_mers(out_10mer, out_9mer, RY10TBL, RY9TBL,10));
-   let q9mer = norm_vector_f(quotient_ry_mers(out_9mer, out_8mer, RY9TBL, RY8TBL,9));
+   let q10mer = normalize(freqs_to_f64(quotient_ry_mers(out_10mer, out_9mer, RY10TBL, RY9TBL,10)), &norms[0]);
+   let q9mer = normalize(freqs_to_f64(quotient_ry_mers(out_9mer, out_8mer, RY9TBL, RY8TBL,9)), &norms[1]);
+
+   let q8mer = normalize(freqs_to_f64(quotient_ry_mers(out_8mer, out_7mer, RY8TBL, RY7TBL,8)), &norms[2]);
+   let q7mer = normalize(freqs_to_f64(quotient_ry_mers(out_7mer, out_6mer, RY7TBL, RY6TBL,7)), &norms[3]);
 
-   let q8mer = norm_vector_f(quotient_ry_mers(out_8mer, out_7mer, RY8TBL, RY7TBL,8));
-   let q7mer = norm_vector_f(quotient_ry_mers(out_7mer, out_6mer, RY7TBL, RY6TBL,7));
+   let out_6mer = normalize(sixmer_counts, &norms[4]);
 
    (q10mer,q9mer,q8mer,q7mer,out_6mer, invalid_count)
 }
 
-fn find_rymers(contig: &str) -> ([f32; 528],[f32; 256], [f32; 136],  [f32; 64],  [f32; 36], usize) {
-    let mut sixmer_counts = [0u32; 36];
-    let mut sevenmer_counts = [0u32; 64];
-    let mut eightmer_counts = [0u32; 136];
-    let mut ninemer_counts = [0u32; 256];
-    let mut tenmer_counts = [0u32; 528];
+fn frac_rymer_counts(counts: RymerCounts) -> FracRymerCounts {
+    (counts_to_f64(counts.0), counts_to_f64(counts.1), counts_to_f64(counts.2), counts_to_f64(counts.3), counts_to_f64(counts.4), counts.5)
+}
 
-    let mut invalid_count = 0;
+// Adds the RY 10 base windows with 1 to max_ambiguous ambiguous bases to counts (see add_ambiguous_windows).
+// Like rymer_counts, the shorter RY mers are the prefixes of each 10 base window.
+fn add_ambiguous_rymers(contig: &[u8], max_ambiguous: usize, counts: &mut FracRymerCounts) {
+    let num_windows = (contig.len() + 1).saturating_sub(10);
+    let bases = AmbiguousBases::new(contig, base2number_ry);
+    counts.5 -= add_ambiguous_windows(&bases, 1, 10, num_windows, max_ambiguous, &RY10TBL, &mut counts.0);
+    add_ambiguous_windows(&bases, 1, 9, num_windows, max_ambiguous, &RY9TBL, &mut counts.1);
+    add_ambiguous_windows(&bases, 1, 8, num_windows, max_ambiguous, &RY8TBL, &mut counts.2);
+    add_ambiguous_windows(&bases, 1, 7, num_windows, max_ambiguous, &RY7TBL, &mut counts.3);
+    add_ambiguous_windows(&bases, 1, 6, num_windows, max_ambiguous, &RY6TBL, &mut counts.4);
+}
+
+fn contig_2_frac_rymer_counts_bytes(contig: &[u8], opts: CountOptions) -> FracRymerCounts {
+    let bases = contig.iter().map(|&b| opts.mask(b));
+    let mut counts = frac_rymer_counts(rymer_counts(bases.clone()));
+    if let Some(max_ambiguous) = opts.max_ambiguous {
+        add_ambiguous_rymers(&bases.collect::<Vec<u8>>(), max_ambiguous, &mut counts);
+    }
+    counts
+}
 
-    let mut fmer = [0u8;10];
+fn find_rymers_bytes(contig: &[u8], norms: &[Normalization], opts: CountOptions) -> RymerDistrs {
+    rymer_distrs_from_counts(contig_2_frac_rymer_counts_bytes(contig, opts), norms)
+}
 
-    for i in 0..9 {
-        fmer[i+1] = contig.as_bytes()[i];
+fn find_rymers(contig: &str, norms: &[Normalization], opts: CountOptions) -> RymerDistrs {
+    let bases = contig.bytes().filter(|&b| b != b'\n').map(|b| opts.mask(b));
+    let mut counts = frac_rymer_counts(rymer_counts(bases.clone()));
+    if let Some(max_ambiguous) = opts.max_ambiguous {
+        add_ambiguous_rymers(&bases.collect::<Vec<u8>>(), max_ambiguous, &mut counts);
     }
+    rymer_distrs_from_counts(counts, norms)
+}
 
-   for b in  contig.as_bytes()[9..].iter().filter(|p| **p != b'\n') {
-       for i in 0..9 {
-           fmer[i] = fmer[i+1];
-       }
-       fmer[9] = *b;
-       match(kmer_to_16_bit_ray_ry::<10>(&fmer)) {
-            Some(f) => {
-                let class = RY10TBL[f as usize];
-                tenmer_counts[class as usize] += 1;
-            },
-            None => {
-                invalid_count += 1;
-            },
-       }
-       match(kmer_to_16_bit_ray_ry::<9>(&fmer)) {
-            Some(f) => {
-                let class = RY9TBL[f as usize];
-                ninemer_counts[class as usize] += 1;
-            },
-            None => (),
+// Maps individual DNA bases to strong (G/C) = 0 / weak (A/T) = 1
+const fn base2number_sw(c: u8) -> Option<u8> {
+    match c {
+        b'G'| b'g' | b'C'| b'c' => Some(0),
+        b'A'| b'a' | b'T'| b't' => Some(1),
+        _ => None,
+    }
+}
+
+// Maps individual DNA bases to keto (G/T) = 0 / amino (A/C) = 1
+const fn base2number_km(c: u8) -> Option<u8> {
+    match c {
+        b'G'| b'g' | b'T'| b't' => Some(0),
+        b'A'| b'a' | b'C'| b'c' => Some(1),
+        _ => None,
+    }
+}
+
+// The three ways to split ACGT in two. RY (purine/pyrimidine) is what the feature vector uses, SW (strong/weak) and
+// KM (keto/amino) are the other two, counted the same way: by default 10,9,8,7,6 mers, the longer ones quotiented.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BinaryAlphabet {
+    RY,
+    SW,
+    KM,
+}
+
+// Binary kmers go up to 16 like ry_rev_comp, tables are 2^k u32s built on first use.
+const MAX_BINARY_K: usize = 16;
+
+static BINARY_TABLES: [[std::sync::OnceLock<Vec<u32>>; MAX_BINARY_K + 1]; 3] = [const { [const { std::sync::OnceLock::new() }; MAX_BINARY_K + 1] }; 3];
+
+// The ks of the blocks, longest first, from k_min to k_max.
+fn binary_ks(k_min: usize, k_max: usize) -> PyResult<Vec<usize>> {
+    if k_min == 0 || k_min > k_max || k_max > MAX_BINARY_K {
+        return Err(PyValueError::new_err(format!("need 1 <= k_min <= k_max <= {}, got k_min = {}, k_max = {}", MAX_BINARY_K, k_min, k_max)));
+    }
+    Ok((k_min..=k_max).rev().collect())
+}
+
+// The ks of the RY blocks of the feature vector, and the default of find_binary_distributions
+const DEFAULT_BINARY_KS: [usize; 5] = [10, 9, 8, 7, 6];
+
+// How the RY blocks are normalized by default: L2 for the quotients, L1 for the shortest k.
+fn binary_norms(num_blocks: usize) -> Vec<Normalization> {
+    (0..num_blocks).map(|i| if i + 1 < num_blocks { Normalization::L2 } else { Normalization::L1 }).collect()
+}
+
+impl BinaryAlphabet {
+    fn new(name: &str) -> PyResult<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "RY" => Ok(BinaryAlphabet::RY),
+            "SW" => Ok(BinaryAlphabet::SW),
+            "KM" => Ok(BinaryAlphabet::KM),
+            _ => Err(PyValueError::new_err(format!("unknown alphabet {:?}, expected \"RY\", \"SW\" or \"KM\"", name))),
         }
-        match(kmer_to_16_bit_ray_ry::<8>(&fmer)) {
-            Some(f) => {
-                let class = RY8TBL[f as usize];
-                eightmer_counts[class as usize] += 1;
-            },
-            None => (),
+    }
+
+    fn letters(self) -> &'static [u8] {
+        match self {
+            BinaryAlphabet::RY => b"RY",
+            BinaryAlphabet::SW => b"SW",
+            BinaryAlphabet::KM => b"KM",
+        }
+    }
+
+    fn encode(self) -> fn(u8) -> Option<u8> {
+        match self {
+            BinaryAlphabet::RY => base2number_ry,
+            BinaryAlphabet::SW => base2number_sw,
+            BinaryAlphabet::KM => base2number_km,
+        }
+    }
+
+    // The complement of a purine is a pyrimidine and of a keto base an amino base, but G <-> C and A <-> T stay strong / weak,
+    // so for SW the reverse compliment is just the reverse.
+    fn complement_flips(self) -> bool {
+        self != BinaryAlphabet::SW
+    }
+
+    // canonical table for 1 <= k <= MAX_BINARY_K, same construction as gen_ry_table (so RY gives the RY*TBL tables)
+    fn table(self, k: usize) -> &'static [u32] {
+        BINARY_TABLES[self as usize][k].get_or_init(|| {
+            let n = 1usize << k;
+            let mut tbl = vec![u32::MAX; n];
+            let mut map_index = 0;
+            for tbl_idx in 0..n {
+                if tbl[tbl_idx] == u32::MAX {
+                    let rev = (tbl_idx as u32).reverse_bits() >> (32 - k);
+                    let rev = if self.complement_flips() { rev ^ low_bits(k) as u32 } else { rev };
+                    tbl[tbl_idx] = map_index;
+                    tbl[rev as usize] = map_index;
+                    map_index += 1;
+                }
+            }
+            tbl
+        })
+    }
+
+    fn block_size(self, k: usize) -> usize {
+        self.table(k).iter().max().map_or(0, |&m| m as usize + 1)
+    }
+}
+
+// binary_counts: kmer counts for each of ks (from binary_ks, so ks[0] is the longest; the shorter ones are prefixes of each
+// ks[0] base window, like rymer_counts) and the number of invalid ks[0] base windows
+fn binary_counts(contig: &[u8], alphabet: BinaryAlphabet, ks: &[usize], opts: CountOptions) -> (Vec<Vec<f64>>, usize) {
+    let encode = alphabet.encode();
+    let k_max = ks[0];
+    let tables = ks.iter().map(|&k| alphabet.table(k)).collect::<Vec<_>>();
+    let mut counts = ks.iter().map(|&k| vec![0.0; alphabet.block_size(k)]).collect::<Vec<_>>();
+    let mut invalid_count = 0;
+
+    let bases = contig.iter().map(|&b| opts.mask(b)).collect::<Vec<u8>>();
+    let mut window = RollingKmer::new(1, k_max);
+    for (pos, &b) in bases.iter().enumerate() {
+        window.push(encode(b));
+        if pos + 1 < k_max {
+            continue;
         }
-        match(kmer_to_16_bit_ray_ry::<7>(&fmer)) {
-            Some(f) => {
-                let class = RY7TBL[f as usize];
-                sevenmer_counts[class as usize] += 1;
+        for (i, &k) in ks.iter().enumerate() {
+            match window.prefix(k) {
+                Some(f) => counts[i][tables[i][f as usize] as usize] += 1.0,
+                None if k == k_max => invalid_count += 1,
+                None => {},
             }
-            None => (),
         }
-        match(kmer_to_16_bit_ray_ry::<6>(&fmer)) {
-            Some(f) => {
-                let class = RY6TBL[f as usize];
-                sixmer_counts[class as usize] += 1;
+    }
+
+    if let Some(max_ambiguous) = opts.max_ambiguous {
+        let num_windows = (bases.len() + 1).saturating_sub(k_max);
+        let ambiguous = AmbiguousBases::new(&bases, encode);
+        for (i, &k) in ks.iter().enumerate() {
+            let added = add_ambiguous_windows(&ambiguous, 1, k, num_windows, max_ambiguous, tables[i], &mut counts[i]);
+            if k == k_max {
+                invalid_count -= added;
             }
-            None => (),
         }
-   }
+    }
+    (counts, invalid_count)
+}
 
+// quotient_ry_mers for any alphabet of `bits` bits per base: divides each canonical k mer frequency in a by the product
+// of the frequencies (in b) of its two overlapping k-1 mers, when that product is above min_denom.
+fn quotient_mers(a: &mut [f32], b: &[f32], a_tbl: &[u32], b_tbl: &[u32], k: usize, bits: usize, min_denom: f32) {
+    let mut aleady_quotiented = vec![false; a.len()];
+    let mask = low_bits(bits * (k - 1));
 
+    for (kmer, &canon_idx) in a_tbl.iter().enumerate() {
+        let canon_idx = canon_idx as usize;
 
-   let out_10mer  = norm_vector(tenmer_counts);
-   let out_9mer  = norm_vector(ninemer_counts);
-   let out_8mer  = norm_vector(eightmer_counts);
-   let out_7mer = norm_vector(sevenmer_counts);
-   let out_6mer = norm_vector(sixmer_counts);
+        if aleady_quotiented[canon_idx] { continue; }
 
-   let q10mer = norm_vector_f(quotient_ry_mers(out_10mer, out_9mer, RY10TBL, RY9TBL,10));
-   let q9mer = norm_vector_f(quotient_ry_mers(out_9mer, out_8mer, RY9TBL, RY8TBL,9));
+        aleady_quotiented[canon_idx] = true;
 
-   let q8mer = norm_vector_f(quotient_ry_mers(out_8mer, out_7mer, RY8TBL, RY7TBL,8));
-   let q7mer = norm_vector_f(quotient_ry_mers(out_7mer, out_6mer, RY7TBL, RY6TBL,7));
+        let (submer_1, submer_2) = (kmer as u64 & mask, (kmer as u64 >> bits) & mask);
 
-   (q10mer,q9mer,q8mer,q7mer,out_6mer, invalid_count)
+        let denom = b[b_tbl[submer_1 as usize] as usize] * b[b_tbl[submer_2 as usize] as usize];
 
+        if denom > min_denom {
+            a[canon_idx] /= denom
+        }
+    }
+}
 
-} 
+// For each of ks, the kmer frequencies quotiented by the k-1 mer frequencies, except for the shortest k (norms: one per k).
+// For ks 10..6 and binary_norms this gives the RY blocks of the feature vector.
+fn binary_distrs(contig: &[u8], alphabet: BinaryAlphabet, ks: &[usize], norms: &[Normalization], opts: CountOptions) -> (Vec<Vec<f32>>, usize) {
+    let (counts, invalid_count) = binary_counts(contig, alphabet, ks, opts);
+    let freqs = counts.iter()
+                      .map(|c| {
+                          let mut out = vec![0.0; c.len()];
+                          normalize_into(c, &Normalization::L1, &mut out);
+                          out
+                      })
+                      .collect::<Vec<_>>();
+
+    let distrs = (0..ks.len())
+        .map(|i| {
+            // the quotients are always taken on L1 normed frequencies, the shortest kmers are normed from their counts
+            let block = match ks[i] {
+                _ if i + 1 == ks.len() => counts[i].clone(),
+                k => {
+                    let mut q = freqs[i].clone();
+                    // Only zero products are skipped, whatever k: a fixed cutoff would skip a different share of the
+                    // kmers for every k. The exception is RY over the default 10 to 6, which has to match the RY blocks
+                    // of the feature vector, and quotient_ry_mers skips products up to 1e-6.
+                    let min_denom = if alphabet == BinaryAlphabet::RY && ks == DEFAULT_BINARY_KS { 0.000001 } else { 0.0 };
+                    quotient_mers(&mut q, &freqs[i + 1], alphabet.table(k), alphabet.table(k - 1), k, 1, min_denom);
+                    q.iter().map(|&x| x as f64).collect()
+                },
+            };
+            let mut out = vec![0.0; block.len()];
+            normalize_into(&block, &norms[i], &mut out);
+            out
+        })
+        .collect();
+    (distrs, invalid_count)
+}
 
 // Parallel read.
 fn par_read(
     file: &mut File,
     read_buffer: &mut Vec<u8>,
     file_size: u64,
-) {
+) -> std::io::Result<()> {
     let chunk_size:u64 = 1024 * 1024 *1024;
     //dbg!("preresize");
     //read_buffer.resize(file_size as usize, 0);
@@ -358,583 +490,2389 @@ fn par_read(
     //         )
     //         .unwrap();
     //     });
-    file.read_to_end(read_buffer).unwrap();
+    file.read_to_end(read_buffer)?;
    // dbg!(&read_buffer[0..150]);
+    Ok(())
 }
 
+// How an input file is compressed, going by its first bytes rather than its name
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Compression {
+    Plain,
+    // also covers bgzip and other multi-member gzip files
+    Gzip,
+    Zstd,
+}
 
-
-
-const FIVEMERTABLE: [u32;1024] = gen_Nmer_table::<1024,5>(FIVEMER_RAY);
-const FOURMERTABLE: [u32;256] = gen_Nmer_table::<256,4>(FOURMER_RAY);
-const THREEMERTABLE: [u32;64] = gen_Nmer_table::<64,3>(THREEMER_RAY);
-const TWOMERTABLE: [u32;16] = gen_Nmer_table::<16,2>(TWOMER_RAY);
-const ONEMERTABLE: [u32;4] = gen_Nmer_table::<4,1>(ONEMER_RAY);
-
-
-const fn gen_multipliers() -> [(u8,u8);136] {
-    let mut result = [(0,0); 136];
-    let mut i = 0;
-    loop {
-        if i >= 256 {
-            break;
-        }
-        let  (mut count_at, mut count_cg) = (0u8,0u8);
-        let four_mer = FOURMER_RAY[i].0.as_bytes();
-
-        match four_mer[0] {
-           b'A'|b'T'=> {
-                count_at += 1;
-            },
-            _ => {
-                count_cg += 1;
-            },
-        }
-        match four_mer[1] {
-            b'A'|b'T' => {
-                count_at += 1;
-            },
-            _ => {
-                count_cg += 1;
-            },
-        }
-        match four_mer[2] {
-            b'A'|b'T' => {
-                count_at += 1;
-            },
-            _ => {
-                count_cg += 1;
-            },
-        }
-        match four_mer[3] {
-            b'A'|b'T' => {
-                count_at += 1;
-            },
-            _ => {
-                count_cg += 1;
-            },
+impl Compression {
+    fn sniff(head: &[u8]) -> Self {
+        if head.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else {
+            Compression::Plain
         }
+    }
+}
 
-        let loc = FOURMER_RAY[i].1;
+// the most a BGZF block decompresses to
+const MAX_BGZF_BLOCK_SIZE: usize = 1 << 16;
 
-        result[loc] = (count_at, count_cg);
-        i += 1;
+// BGZF (bgzip) blocks are gzip members that start with a "BC" extra field holding the size of the block, so they can be
+// found without decompressing anything. Returns (length, decompressed size) of the block buf starts with, None if buf
+// doesn't start with a whole BGZF block.
+// A block can't hold more than 64 KB, a larger size field means buf is something else (or corrupt) and is read serially.
+fn bgzf_block_len(buf: &[u8]) -> Option<(usize, usize)> {
+    let header = buf.get(..18)?;
+    if !is_bgzf_header(header) {
+        return None;
     }
-    result
+    let len = u16::from_le_bytes([header[16], header[17]]) as usize + 1;
+    if len < 26 {
+        return None;
+    }
+    // the last 4 bytes of a gzip member are its decompressed size (mod 2^32, but BGZF blocks are <= 64 KB)
+    let trailer = buf.get(len - 4..len)?;
+    let size = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]) as usize;
+    (size <= MAX_BGZF_BLOCK_SIZE).then_some((len, size))
 }
-const MULTIPLIERS:[(u8,u8);136] = gen_multipliers();
 
-// /// Maps individual DNA bases from utf-8 to 2 bit representation
-const fn base2number(c: u8) -> Option<u8> {
-    match c {
-        b'A'| b'a' => Some(0),
-        b'T'| b't' => Some(1),
-        b'C'| b'c' => Some(2),
-        b'G'| b'g' => Some(3),
-        _ => None,
+// (start, end, decompressed size) of the whole BGZF blocks at the start of buf, and where the first byte after them is.
+fn bgzf_prefix(buf: &[u8]) -> (Vec<(usize, usize, usize)>, usize) {
+    let mut blocks = Vec::new();
+    let mut start = 0;
+    while let Some((len, size)) = bgzf_block_len(&buf[start..]) {
+        blocks.push((start, start + len, size));
+        start += len;
     }
+    (blocks, start)
 }
 
-const fn base2number_revcomp(c: u8) -> Option<u8> {
-    match c {
-        b'A'| b'a' => Some(1),
-        b'T'| b't' => Some(0),
-        b'C'| b'c' => Some(3),
-        b'G'| b'g' => Some(2),
-        _ => None,
-    }
+fn is_bgzf_header(head: &[u8]) -> bool {
+    head.len() >= 16 && head[..4] == [0x1f, 0x8b, 8, 4] && head[12..16] == [b'B', b'C', 2, 0]
 }
 
-/// Maps 4mer from utf-8 to 8 bit representation
-const fn kmer_to_16_bit<const N: usize>( mer:  & str) -> Option<u32> {
-    let mut result: u32 = 0;
+// Every block of buf (see bgzf_block_len), None if buf isn't BGZF.
+fn bgzf_blocks(buf: &[u8]) -> Option<Vec<(usize, usize, usize)>> {
+    let (blocks, end) = bgzf_prefix(buf);
+    (end == buf.len()).then_some(blocks)
+}
 
-    let b = mer.as_bytes();
-    let mut i = 0;
-    loop {
-        if i >= N {
-            break;
-        }
-        let c = b[i];
-        result = result << 2;
-        result |= match base2number(c) {
-            Some(b) => b as u32,
-            None => {
-                return None;
-            }
-        };
-        i += 1;
+// Decompresses all BGZF blocks in parallel, each straight into its part of the output.
+fn decompress_bgzf(buf: &[u8], blocks: &[(usize, usize, usize)]) -> std::io::Result<Vec<u8>> {
+    let mut decom_buffer = vec![0u8; blocks.iter().map(|block| block.2).sum()];
+    let mut outputs = Vec::with_capacity(blocks.len());
+    let mut rest = &mut decom_buffer[..];
+    for &(_, _, size) in blocks {
+        let (output, tail) = rest.split_at_mut(size);
+        outputs.push(output);
+        rest = tail;
     }
-
-    Some(result)
+    blocks.par_iter()
+          .zip(outputs)
+          .try_for_each(|(&(start, end, _), mut output)| {
+              // reads to the end of the member, so its CRC is checked too
+              std::io::copy(&mut GzDecoder::new(&buf[start..end]), &mut output)?;
+              if output.is_empty() {
+                  Ok(())
+              } else {
+                  Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "BGZF block is shorter than its size field"))
+              }
+          })?;
+    Ok(decom_buffer)
 }
 
+// The gzip member buf starts with, decompressed, and its length. The decoder checks the CRC and ISIZE of the member.
+fn gzip_member(buf: &[u8]) -> std::io::Result<(usize, Vec<u8>)> {
+    let mut decoder = flate2::bufread::GzDecoder::new(buf);
+    let mut member = Vec::new();
+    decoder.read_to_end(&mut member)?;
+    Ok((buf.len() - decoder.into_inner().len(), member))
+}
 
-/// Maps 4mer from utf-8 to 8 bit representation
-const fn kmer_to_16_bit_ray<const N: usize>( mer:  & [u8]) -> Option<u32> {
-    let mut result: u32 = 0;
-    let mut i = 0;
-    loop {
-        if i >= N {
-            break;
-        }
-        let c = mer[i];
-        result = result << 2;
-        result |= match base2number(c) {
-            Some(b) => b as u32,
-            None => {
-                return None;
-            }
+// Gzip files with several members that aren't BGZF (e.g. cat a.gz b.gz, or pigz --independent) don't say where their
+// members start, so every 1f 8b 08 in buf is tried as the start of a member, in parallel. The members are then followed
+// from the start of buf, so a candidate that isn't really a member start is never used, and a member that is corrupt
+// doesn't decompress. Anything else (e.g. trailing garbage) is left to MultiGzDecoder, on one core.
+fn decompress_gzip_members(buf: &[u8]) -> std::io::Result<Vec<u8>> {
+    let candidates = (0..buf.len().saturating_sub(2)).into_par_iter()
+                                                     .filter(|&i| buf[i..i + 3] == [0x1f, 0x8b, 8])
+                                                     .collect::<Vec<usize>>();
+    let mut members = candidates.par_iter()
+                                .filter_map(|&start| gzip_member(&buf[start..]).ok().map(|(len, member)| (start, (start + len, member))))
+                                .collect::<HashMap<usize, (usize, Vec<u8>)>>();
+    let mut chain = Vec::new();
+    let mut start = 0;
+    while start < buf.len() {
+        let Some((end, member)) = members.remove(&start) else {
+            let mut decom_buffer = Vec::new();
+            MultiGzDecoder::new(buf).read_to_end(&mut decom_buffer)?;
+            return Ok(decom_buffer);
         };
-        i += 1;
+        chain.push(member);
+        start = end;
+    }
+    drop(members);
+    if chain.len() == 1 {
+        return Ok(chain.pop().unwrap());
+    }
+    // copied into place one member at a time, each freed right after, so the file is held about once
+    let mut decom_buffer = Vec::with_capacity(chain.iter().map(|member| member.len()).sum());
+    for member in chain {
+        decom_buffer.extend_from_slice(&member);
     }
+    Ok(decom_buffer)
+}
 
-    Some(result)
+// The contents of a (plain, gzip, bgzip or zstd compressed) file as read into memory, decompressed.
+// bgzip files and gzip files with several members are decompressed in parallel, a single gzip member or zstd on one core.
+// The whole file is decompressed before any of it is parsed, see open_contig_file for reading it as it is decompressed.
+fn decompress(read_buffer: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    let mut decom_buffer: Vec<u8> = Vec::new();
+    match Compression::sniff(&read_buffer) {
+        Compression::Plain => return Ok(read_buffer),
+        Compression::Gzip => match bgzf_blocks(&read_buffer) {
+            Some(blocks) => decompress_bgzf(&read_buffer, &blocks).map(|decompressed| decom_buffer = decompressed),
+            None => decompress_gzip_members(&read_buffer).map(|decompressed| decom_buffer = decompressed),
+        },
+        Compression::Zstd => zstd::stream::read::Decoder::new(&read_buffer[..]).and_then(|mut zst| zst.read_to_end(&mut decom_buffer)).map(|_| ()),
+    }?;
+    Ok(decom_buffer)
 }
 
+// Reads a whole (optionally compressed, see Compression) fasta into memory.
+fn read_contig_file(contig_file: &str) -> std::io::Result<Vec<u8>> {
+    let file_size = fs::metadata(contig_file)?.len();
+    let mut file = File::open(contig_file)?;
 
-const fn kmer_to_16_bit_ray_revcomp<const N: usize>( mer:  & [u8]) -> Option<u32> {
-    let mut result: u32 = 0;
-    let mut i = 0;
-    loop {
-        if i >= N {
-            break;
-        }
-        let c = mer[N-i -1];
-        result = result << 2;
-        result |= match base2number_revcomp(c) {
-            Some(b) => b as u32,
-            None => {
-                return None;
-            }
-        };
-        i += 1;
-    }
+    let mut read_buffer: Vec<u8> = Vec::new();
+    par_read(&mut file, &mut read_buffer, file_size)?;
 
-    Some(result)
+    decompress(read_buffer)
 }
 
+// The error the pyfns raise when a contig file can't be read, with the file it was about.
+fn contig_file_error(contig_file: &str, e: std::io::Error) -> PyErr {
+    PyIOError::new_err(format!("failed to read {}: {}", contig_file, e))
+}
 
+// read_contig_file for the pyfns, without holding the GIL.
+fn load_contig_file(py: Python, contig_file: &str) -> PyResult<Vec<u8>> {
+    py.allow_threads(|| read_contig_file(contig_file)).map_err(|e| contig_file_error(contig_file, e))
+}
 
-const fn gen_Nmer_table<const N: usize, const K: usize>(mapping: [(&'static str, usize);N] ) -> [u32; N] {
-    let mut result = [0u32; N];
+// The parse_contigs of a fasta or fastq file for the pyfns, without holding the GIL. The file's text is freed once parsed.
+fn load_contigs(py: Python, contig_file: &str, min_len: usize) -> PyResult<Vec<(String, Vec<u8>)>> {
+    py.allow_threads(|| read_contig_file(contig_file).and_then(|read_buffer| parse_contigs(&read_buffer, min_len)))
+      .map_err(|e| contig_file_error(contig_file, e))
+}
 
-    let mut i: usize = 0;
+// Calls f with the records of fasta read from reader (see fasta_records), about batch_size bytes of them at a time (but
+// at least one record). Only one batch is held in memory besides what reader holds, e.g. a file opened with
+// open_contig_file. A whole fasta in memory is better split with fasta_records directly, which is parallel and doesn't copy it.
+// The reads of a fastq are passed on as fasta records, with the low quality bases masked (see read_fastq_record).
+fn for_each_record_batch(reader: &mut impl BufRead, batch_size: usize, min_quality: Option<u8>, mut f: impl FnMut(&[FastaRecord])) -> std::io::Result<()> {
+    let fastq = records_are_fastq(reader)?;
+    let mut text: Vec<u8> = Vec::new();
+    let mut next_header = Vec::new();
     loop {
-        if i >= N {
-            break;
+        if fastq {
+            let Some((name, read)) = read_fastq_record(reader, min_quality)? else { break };
+            text.push(b'>');
+            text.extend_from_slice(&name);
+            text.push(b'\n');
+            text.extend_from_slice(&read);
+            text.push(b'\n');
+        } else {
+            let Some(record) = read_fasta_record(reader, &mut next_header)? else { break };
+            text.push(b'>');
+            text.extend_from_slice(&record);
+            text.push(b'\n');
         }
-        match kmer_to_16_bit::<K>(mapping[i].0) {
-            Some(x) => {
-                result[x as usize] = mapping[i].1 as u32;
-            } 
-            None => unreachable!(),
+        if text.len() >= batch_size {
+            f(&fasta_records(&text));
+            text.clear();
         }
-        i += 1;
     }
+    if !text.is_empty() {
+        f(&fasta_records(&text));
+    }
+    Ok(())
+}
 
-    result
+// about how many compressed bytes of BGZF blocks BgzfReader decompresses at a time
+const BGZF_BATCH_SIZE: usize = 1 << 24;
+
+// Streams a BGZF file, decompressing about batch_size bytes of blocks at a time in parallel (see decompress_bgzf). If
+// the file turns out not to be all BGZF, the rest of it is read with MultiGzDecoder.
+struct BgzfReader<R> {
+    batch_size: usize,
+    // None once the rest of the file is read by serial
+    inner: Option<R>,
+    // what is read from inner but not decompressed yet, at most the start of a block between batches
+    compressed: Vec<u8>,
+    decompressed: Vec<u8>,
+    pos: usize,
+    serial: Option<SerialGzReader<R>>,
 }
 
-fn norm_vector<const N: usize>(in_vec: [u32;N]) -> [f32; N] {
-    let sum = in_vec.iter()
-                    .map(|&x| x as u64)
-                    .sum::<u64>();
-    
-    let mut result =  [0.0; N];
+// what BgzfReader reads the rest of a file with once it isn't BGZF: the bytes it had read already, then the rest of inner
+type SerialGzReader<R> = BufReader<MultiGzDecoder<std::io::Chain<std::io::Cursor<Vec<u8>>, R>>>;
 
-    for i in 0..N {
-        result[i] = ((in_vec[i] as f64)/(sum as f64)) as f32;
+impl<R: Read> BgzfReader<R> {
+    fn new(inner: R, batch_size: usize) -> Self {
+        BgzfReader { batch_size, inner: Some(inner), compressed: Vec::new(), decompressed: Vec::new(), pos: 0, serial: None }
     }
 
-    result
+    fn next_batch(&mut self) -> std::io::Result<()> {
+        self.decompressed.clear();
+        self.pos = 0;
+        let Some(inner) = self.inner.as_mut() else { return Ok(()) };
+        loop {
+            let read = inner.by_ref().take(self.batch_size as u64).read_to_end(&mut self.compressed)?;
+            let (blocks, end) = bgzf_prefix(&self.compressed);
+            if !blocks.is_empty() {
+                self.decompressed = decompress_bgzf(&self.compressed[..end], &blocks)?;
+                self.compressed.drain(..end);
+                return Ok(());
+            }
+            // a BGZF block is at most 64 KB long, so anything else isn't one
+            if read == 0 || self.compressed.len() > 1 << 16 {
+                if !self.compressed.is_empty() {
+                    let rest = std::io::Cursor::new(std::mem::take(&mut self.compressed));
+                    self.serial = Some(BufReader::new(MultiGzDecoder::new(rest.chain(self.inner.take().unwrap()))));
+                }
+                return Ok(());
+            }
+        }
+    }
 }
 
+impl<R: Read> Read for BgzfReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.fill_buf()?.read(buf)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
 
-fn norm_vector_f<const N: usize>(in_vec: [f32;N]) -> [f32; N] {
-    let sum = in_vec.iter()
-                    .map(|&x| (x as f64)*(x as f64))
-                    .sum::<f64>()
-                    .sqrt();
-    
-    let mut result =  [0.0; N];
-
-    if sum > 0. {
-        for i in 0..N {
-            result[i] = ((in_vec[i] as f64)/(sum as f64)) as f32;
+impl<R: Read> BufRead for BgzfReader<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        if self.serial.is_none() && self.pos == self.decompressed.len() {
+            self.next_batch()?;
+        }
+        match &mut self.serial {
+            Some(serial) => serial.fill_buf(),
+            None => Ok(&self.decompressed[self.pos..]),
         }
     }
 
-    result
+    fn consume(&mut self, amt: usize) {
+        match &mut self.serial {
+            Some(serial) => serial.consume(amt),
+            None => self.pos += amt,
+        }
+    }
 }
 
+// A buffered reader over the decompressed contents of a file, for the readers that stream it (see FastaIterator).
+// bgzip files are decompressed in parallel, a batch at a time (see BgzfReader), and parsed as they are decompressed.
+fn open_contig_file(contig_file: &str) -> std::io::Result<Box<dyn BufRead + Send>> {
+    let mut reader = BufReader::new(File::open(contig_file)?);
+    let head = reader.fill_buf()?;
+    Ok(match Compression::sniff(head) {
+        Compression::Plain => Box::new(reader),
+        Compression::Gzip if is_bgzf_header(head) => Box::new(BgzfReader::new(reader, BGZF_BATCH_SIZE)),
+        Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+        Compression::Zstd => Box::new(BufReader::new(zstd::stream::read::Decoder::with_buffer(reader)?)),
+    })
+}
 
+// One record of fasta text, see fasta_records. Every reader of fasta goes through this, so they all agree on what a
+// contig is: \n or \r\n line breaks, blank lines, header only records and a missing last newline are all fine.
+#[derive(Clone, Copy, Debug)]
+struct FastaRecord<'a> {
+    // the header up to the first space or tab
+    name: &'a [u8],
+    // the lines after the header, still with their line breaks (see bases and lines)
+    seq: &'a [u8],
+}
 
-fn contig_to_labeled(contig: &[u8]) -> [u32;2000] {
-    let mut result = [0u32;2000];
-    
-    for (idx, chunk) in contig.windows(5).enumerate() {
-        result[idx] = kmer_to_16_bit_ray::<5>(chunk).unwrap_or_else( || 1024) as u32;
+impl<'a> FastaRecord<'a> {
+    // record: the text of a record after its '>'
+    fn parse(record: &'a [u8]) -> Self {
+        let end_of_header = record.iter().position(|&x| x == b'\n').unwrap_or(record.len());
+        let header = trim_line_end(&record[..end_of_header]);
+        FastaRecord { name: header_name(header), seq: record.get(end_of_header + 1..).unwrap_or_default() }
     }
-    result
-}
 
+    fn name(&self) -> String {
+        String::from_utf8_lossy(self.name).into_owned()
+    }
 
-fn contig_to_labeled_flip(contig: &[u8],flip: bool) -> [u32;2000] {
-    let mut result = [0u32;2000];
+    fn bases(&self) -> impl Iterator<Item = u8> + Clone + 'a {
+        self.seq.iter().copied().filter(|&x| x != b'\n' && x != b'\r')
+    }
 
-    // let max = if contig.len() < 2000 { contig.len() } else { 2000 };
-    if flip {
-        let window_size = 5;
-        for (idx, i) in (window_size..=contig.len()).rev().enumerate() {
-            let window = &contig[i - window_size..i];
-            result[idx] = kmer_to_16_bit_ray_revcomp::<5>(window).unwrap_or_else( || 1024) as u32;
-          }
-    } else {
+    fn sequence(&self) -> Vec<u8> {
+        self.bases().collect()
+    }
 
-        for (idx, chunk) in contig.windows(5).enumerate() {
-            result[idx] = kmer_to_16_bit_ray::<5>(chunk).unwrap_or_else( || 1024) as u32;
-        }   
+    // the sequence lines as they were wrapped, without line breaks and blank lines
+    fn lines(&self) -> impl Iterator<Item = &'a [u8]> {
+        self.seq.split(|&x| x == b'\n').map(trim_line_end).filter(|line| !line.is_empty())
     }
-    result
-    
 }
 
+// Every record of fasta text. A record starts at a '>' at the start of a line and runs to the next one, so a '>' in a
+// header doesn't split it, and anything before the first record (e.g. blank lines) is skipped.
+fn fasta_records(fasta: &[u8]) -> Vec<FastaRecord<'_>> {
+    let fasta = strip_bom(fasta);
+    const CHUNK: usize = 1 << 20;
+    let mut starts = fasta.par_chunks(CHUNK)
+                          .enumerate()
+                          .flat_map_iter(|(c, chunk)| {
+                              chunk.iter()
+                                   .enumerate()
+                                   .map(move |(i, &x)| (c * CHUNK + i, x))
+                                   .filter(|&(pos, x)| x == b'>' && (pos == 0 || fasta[pos - 1] == b'\n'))
+                                   .map(|(pos, _)| pos)
+                          })
+                          .collect::<Vec<usize>>();
+    starts.push(fasta.len());
+    starts.par_windows(2)
+          .map(|record| FastaRecord::parse(&fasta[record[0] + 1..record[1]]))
+          .collect()
+}
 
-fn contig_to_labeled2(contig: &[u8]) -> [u32;2000] {
-    let mut result = [0u32;2000];
-    // let max = if contig.len() < 2000 { contig.len() } else { 2000 };
-    for (idx, chunk) in contig.chunks(1).enumerate() {
-        result[idx] = kmer_to_16_bit_ray::<1>(chunk).unwrap_or_else( || 4) as u32;
+// The text of the next record of a fasta stream, after its '>' (see FastaRecord::parse). next_header keeps the line
+// that starts the record after it, which has to be read to know where this one ends. None at the end of the file.
+fn read_fasta_record(reader: &mut impl BufRead, next_header: &mut Vec<u8>) -> std::io::Result<Option<Vec<u8>>> {
+    // anything before the first record is skipped
+    while next_header.first() != Some(&b'>') {
+        next_header.clear();
+        if reader.read_until(b'\n', next_header)? == 0 {
+            return Ok(None);
+        }
     }
-    result
+    let mut record = next_header[1..].to_vec();
+    next_header.clear();
+    loop {
+        let start = record.len();
+        if reader.read_until(b'\n', &mut record)? == 0 {
+            break;
+        }
+        if record[start] == b'>' {
+            next_header.extend_from_slice(&record[start..]);
+            record.truncate(start);
+            break;
+        }
+    }
+    Ok(Some(record))
 }
 
+// Splits fasta text into (contig name, contig with line breaks removed) for every contig of at least min_len bases.
+// fastq text gives (read name, read) instead, the qualities are ignored (see parse_fastq).
+fn parse_contigs(buf: &[u8], min_len: usize) -> std::io::Result<Vec<(String, Vec<u8>)>> {
+    if is_fastq(buf) {
+        return parse_fastq(buf, min_len, None);
+    }
+    Ok(fasta_records(buf).par_iter()
+                         .map(|record| (record.name(), record.sequence()))
+                         .filter(|(_, contig)| contig.len() >= min_len)
+                         .collect())
+}
 
+// Phred qualities are stored as the score plus 33
+const PHRED_OFFSET: u8 = 33;
 
+// Some editors on Windows start text files with a UTF-8 byte order mark
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
 
-fn contig_2_nmer_distrs_bytes(contig: &[u8]) -> ([f32; 512], [f32; 136], [f32; 136], [f32; 32], [f32; 10], [f32; 2], usize) {
-    let mut onemer_counts = [0u32; 2];
-    let mut twomer_counts = [0u32; 10];
-    let mut threemer_counts = [0u32; 32];
-    let mut fourmer_counts = [0u32; 136];
-    let mut fivemer_counts = [0u32; 512];
-
-    let mut invalid_count = 0;
+fn strip_bom(buf: &[u8]) -> &[u8] {
+    buf.strip_prefix(UTF8_BOM).unwrap_or(buf)
+}
 
-    let mut fmer = [
-        0,
-        contig[0],
-        contig[1],
-        contig[2],
-        contig[3],
-    ];
+// Whether text is fastq: its first character (after a byte order mark and blank lines) is '@'.
+fn is_fastq(buf: &[u8]) -> bool {
+    strip_bom(buf).iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'@')
+}
 
-    for b in contig[4..].iter() {
-        fmer[0] = fmer[1];
-        fmer[1] = fmer[2];
-        fmer[2] = fmer[3];
-        fmer[3] = fmer[4];
-        fmer[4] = *b;
-    
+// is_fastq for a stream, skipping its byte order mark so the record readers don't see it.
+fn records_are_fastq(reader: &mut impl BufRead) -> std::io::Result<bool> {
+    if reader.fill_buf()?.starts_with(UTF8_BOM) {
+        reader.consume(UTF8_BOM.len());
+    }
+    Ok(is_fastq(reader.fill_buf()?))
+}
 
-        match kmer_to_16_bit_ray::<5>(&fmer) {
-            Some(f) => {
-                let class = FIVEMERTABLE[f as usize];
-                fivemer_counts[class as usize] += 1;
-            }
-            None => {
-                invalid_count += 1;
-            },
-        }
-    
-        match kmer_to_16_bit_ray::<4>(&fmer[..4]) {
-            Some(f) => {
-                let class = FOURMERTABLE[f as usize];
-                fourmer_counts[class as usize] += 1;
-            }
-            None => (),
-        }
-    
-        match kmer_to_16_bit_ray::<3>(&fmer[..3]) {
-            Some(f) => {
-                let class = THREEMERTABLE[f as usize];
-                threemer_counts[class as usize] += 1;
+// Bases with a Phred quality below min_quality become N, so they count like any other invalid base.
+fn mask_low_quality(seq: &mut [u8], qual: &[u8], min_quality: Option<u8>) {
+    if let Some(min_quality) = min_quality {
+        for (b, &q) in seq.iter_mut().zip(qual) {
+            if q.saturating_sub(PHRED_OFFSET) < min_quality {
+                *b = b'N';
             }
-            None => (),
         }
+    }
+}
 
-        match kmer_to_16_bit_ray::<2>(&fmer[..2]) {
-            Some(f) => {
-                let class = TWOMERTABLE[f as usize];
-                twomer_counts[class as usize] += 1;
-            }
-            None => (),
-        }
+fn trim_line_end(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
 
-        match kmer_to_16_bit_ray::<1>(&fmer[..1]) {
-            Some(f) => {
-                let class = ONEMERTABLE[f as usize];
-                onemer_counts[class as usize] += 1;
-            }
-            None => (),
-        }
+// The name of a fasta or fastq record: its header (without the '>' or '@') up to the first space or tab.
+fn header_name(header: &[u8]) -> &[u8] {
+    let end_of_name = header.iter().position(|&x| x == b' ' || x == b'\t').unwrap_or(header.len());
+    &header[..end_of_name]
+}
 
+// Reads a line into line without its \n or \r\n, false at the end of the file.
+fn read_trimmed_line(reader: &mut impl BufRead, line: &mut Vec<u8>) -> std::io::Result<bool> {
+    line.clear();
+    if reader.read_until(b'\n', line)? == 0 {
+        return Ok(false);
     }
-
-    
-    match kmer_to_16_bit_ray::<4>(&fmer[1..5]) {
-        Some(f) => {
-            let class = FOURMERTABLE[f as usize];
-            fourmer_counts[class as usize] += 1;
-        }
-        None => (),
+    if line.last() == Some(&b'\n') {
+        line.pop();
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
     }
+    Ok(true)
+}
 
-    for i in 1..3 {
-        match kmer_to_16_bit_ray::<3>(&fmer[i..3+i]) {
-            Some(f) => {
-                let class = THREEMERTABLE[f as usize];
-                threemer_counts[class as usize] += 1;
-            }
-            None => (),
+// The next (read name, read) of a fastq, with the bases below min_quality masked (see mask_low_quality). Every reader of
+// fastq goes through this. A record is 4 lines: '@' and the header, the read (not wrapped), a line starting with '+' and
+// the qualities, as many as there are bases. Blank lines between records are skipped, anything else is an InvalidData
+// error rather than being read out of step. None at the end of the file.
+fn read_fastq_record(reader: &mut impl BufRead, min_quality: Option<u8>) -> std::io::Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let mut header = Vec::new();
+    loop {
+        if !read_trimmed_line(reader, &mut header)? {
+            return Ok(None);
         }
-    }
-    for i in 1..4 {
-        match kmer_to_16_bit_ray::<2>(&fmer[i..2+i]) {
-            Some(f) => {
-                let class = TWOMERTABLE[f as usize];
-                twomer_counts[class as usize] += 1;
-            }
-            None => (),
+        if !header.is_empty() {
+            break;
         }
     }
-    for i in 1..5 {
-        match kmer_to_16_bit_ray::<1>(&fmer[i..1+i]) {
-            Some(f) => {
-                let class = ONEMERTABLE[f as usize];
-                onemer_counts[class as usize] += 1;
-            }
-            None => (),
+    let mut seq = Vec::new();
+    let mut plus = Vec::new();
+    let mut qual = Vec::new();
+    let complete = read_trimmed_line(reader, &mut seq)? && read_trimmed_line(reader, &mut plus)? && read_trimmed_line(reader, &mut qual)?;
+    let problem = if header[0] != b'@' {
+        Some("the header doesn't start with '@'")
+    } else if !complete {
+        Some("the file ends inside the record")
+    } else if plus.first() != Some(&b'+') {
+        Some("the third line doesn't start with '+'")
+    } else if qual.len() != seq.len() {
+        Some("the read and its qualities differ in length")
+    } else {
+        None
+    };
+    if let Some(problem) = problem {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                                       format!("malformed fastq record {:?}: {}", String::from_utf8_lossy(&header), problem)));
+    }
+    mask_low_quality(&mut seq, &qual, min_quality);
+    Ok(Some((header_name(&header[1..]).to_vec(), seq)))
+}
+
+// Splits fastq text into (read name, read) for every read of at least min_len bases, like parse_contigs does for fasta.
+fn parse_fastq(buf: &[u8], min_len: usize, min_quality: Option<u8>) -> std::io::Result<Vec<(String, Vec<u8>)>> {
+    let mut reader = strip_bom(buf);
+    let mut reads = Vec::new();
+    while let Some((name, seq)) = read_fastq_record(&mut reader, min_quality)? {
+        if seq.len() >= min_len {
+            reads.push((String::from_utf8_lossy(&name).into_owned(), seq));
         }
     }
+    Ok(reads)
+}
 
 
 
-    let out_5mer  = norm_vector(fivemer_counts);
-    let out_4mer  = norm_vector(fourmer_counts);
-    let out_3mer  = norm_vector(threemer_counts);
-    let out_2mer = norm_vector(twomer_counts);
-    let out_1mer = norm_vector(onemer_counts);
 
-    let mut fourmer_multipliers =  [0f64;136];
-    for i in 0..136 {
-        let mut denom: f64 = 1.0;
-        for _ in 0..MULTIPLIERS[i].0 {
-            denom *= out_1mer[0] as f64;
-           // assert!(onemer_counts[0]  != 0);
-        }
-        for _ in 0..MULTIPLIERS[i].1 {
-            denom *= out_1mer[1] as f64;
-          //  assert!(onemer_counts[1]  != 0);
 
-        }
-        fourmer_multipliers[i] = denom as f64;
+const FIVEMERTABLE: [u32;1024] = gen_Nmer_table::<1024,5>(FIVEMER_RAY);
+const FOURMERTABLE: [u32;256] = gen_Nmer_table::<256,4>(FOURMER_RAY);
+const THREEMERTABLE: [u32;64] = gen_Nmer_table::<64,3>(THREEMER_RAY);
+const TWOMERTABLE: [u32;16] = gen_Nmer_table::<16,2>(TWOMER_RAY);
+const ONEMERTABLE: [u32;4] = gen_Nmer_table::<4,1>(ONEMER_RAY);
+
+
+
+// /// Maps individual DNA bases from utf-8 to 2 bit representation
+const fn base2number(c: u8) -> Option<u8> {
+    match c {
+        b'A'| b'a' => Some(0),
+        b'T'| b't' => Some(1),
+        b'C'| b'c' => Some(2),
+        b'G'| b'g' => Some(3),
+        _ => None,
+    }
+}
+
+const fn base2number_revcomp(c: u8) -> Option<u8> {
+    match c {
+        b'A'| b'a' => Some(1),
+        b'T'| b't' => Some(0),
+        b'C'| b'c' => Some(3),
+        b'G'| b'g' => Some(2),
+        _ => None,
     }
+}
 
-    let mut out_l4n1 = [0.0f32; 136];
+/// Maps 4mer from utf-8 to 8 bit representation
+const fn kmer_to_16_bit<const N: usize>( mer:  & str) -> Option<u32> {
+    let mut result: u32 = 0;
 
-    for i in 0..136 {
-        let denom = fourmer_multipliers[i];
-        // if denom == 0 and fourmer_counts[i] != 0 {
-        //     panic!();
-        // }
-        out_l4n1[i] = if denom > 0.0 {
-            ((out_4mer[i] as f64)/denom) as f32
-        } else {
-            if out_4mer[i]  > 0.0 {
-                dbg!(&fourmer_counts[i]);
-                dbg!(&fourmer_multipliers[i]);
-                panic!();
+    let b = mer.as_bytes();
+    let mut i = 0;
+    loop {
+        if i >= N {
+            break;
+        }
+        let c = b[i];
+        result = result << 2;
+        result |= match base2number(c) {
+            Some(b) => b as u32,
+            None => {
+                return None;
             }
-            0.0
         };
+        i += 1;
     }
 
-    (out_5mer, out_4mer, out_4mer, out_3mer, out_2mer, out_1mer, invalid_count)
+    Some(result)
 }
 
 
-// /// Given a contig of DNA bases, calculates L2 normed
-// /// "Distribution" over contigs 4mer count, where 4mers
-// /// are quotiented by reverse complement.
-fn contig_2_nmer_distrs(contig: &str) -> ([f32; 512], [f32; 136], [f32; 136], [f32; 32], [f32; 10], [f32; 2], usize) {
-    let mut onemer_counts = [0u32; 2];
-    let mut twomer_counts = [0u32; 10];
-    let mut threemer_counts = [0u32; 32];
-    let mut fourmer_counts = [0u32; 136];
-    let mut fivemer_counts = [0u32; 512];
-
-    let mut invalid_count = 0;
+/// Maps 4mer from utf-8 to 8 bit representation
+const fn kmer_to_16_bit_ray<const N: usize>( mer:  & [u8]) -> Option<u32> {
+    let mut result: u32 = 0;
+    let mut i = 0;
+    loop {
+        if i >= N {
+            break;
+        }
+        let c = mer[i];
+        result = result << 2;
+        result |= match base2number(c) {
+            Some(b) => b as u32,
+            None => {
+                return None;
+            }
+        };
+        i += 1;
+    }
 
-    let mut fmer = [
-        0,
-        contig.as_bytes()[0],
-        contig.as_bytes()[1],
-        contig.as_bytes()[2],
-        contig.as_bytes()[3],
-    ];
+    Some(result)
+}
 
-    for b in contig.as_bytes()[4..].iter().filter(|p| **p != b'\n') {
-        fmer[0] = fmer[1];
-        fmer[1] = fmer[2];
-        fmer[2] = fmer[3];
-        fmer[3] = fmer[4];
-        fmer[4] = *b;
-    
 
-        match kmer_to_16_bit_ray::<5>(&fmer) {
-            Some(f) => {
-                let class = FIVEMERTABLE[f as usize];
-                fivemer_counts[class as usize] += 1;
-            }
-            None => {
-                invalid_count += 1;
-            },
-        }
-    
-        match kmer_to_16_bit_ray::<4>(&fmer[..4]) {
-            Some(f) => {
-                let class = FOURMERTABLE[f as usize];
-                fourmer_counts[class as usize] += 1;
-            }
-            None => (),
+const fn kmer_to_16_bit_ray_revcomp<const N: usize>( mer:  & [u8]) -> Option<u32> {
+    let mut result: u32 = 0;
+    let mut i = 0;
+    loop {
+        if i >= N {
+            break;
         }
-    
-        match kmer_to_16_bit_ray::<3>(&fmer[..3]) {
-            Some(f) => {
-                let class = THREEMERTABLE[f as usize];
-                threemer_counts[class as usize] += 1;
+        let c = mer[N-i -1];
+        result = result << 2;
+        result |= match base2number_revcomp(c) {
+            Some(b) => b as u32,
+            None => {
+                return None;
             }
-            None => (),
-        }
+        };
+        i += 1;
+    }
 
-        match kmer_to_16_bit_ray::<2>(&fmer[..2]) {
-            Some(f) => {
-                let class = TWOMERTABLE[f as usize];
-                twomer_counts[class as usize] += 1;
-            }
-            None => (),
-        }
+    Some(result)
+}
 
-        match kmer_to_16_bit_ray::<1>(&fmer[..1]) {
-            Some(f) => {
-                let class = ONEMERTABLE[f as usize];
-                onemer_counts[class as usize] += 1;
-            }
-            None => (),
+// The sample functions redraw samples with invalid RY 10 base windows. When masked windows are skipped they are counted as
+// invalid too, so this recounts without the masking (only when skipping, since it is another pass over the sample).
+fn unmasked_invalid_windows(ctg: &[u8], invalid_count: usize, opts: CountOptions) -> usize {
+    if !opts.skip_masked {
+        return invalid_count;
+    }
+    let mut counts = frac_rymer_counts(rymer_counts(ctg.iter().copied()));
+    if let Some(max_ambiguous) = opts.max_ambiguous {
+        add_ambiguous_rymers(ctg, max_ambiguous, &mut counts);
+    }
+    counts.5
+}
+
+// The most ambiguous bases max_ambiguous can allow in a window, see add_ambiguous_windows
+const MAX_AMBIGUOUS: usize = 4;
+
+// Options for how the 5,4,3,2,1 mer and RY mer counters treat bases other than ACGT.
+#[derive(Clone, Copy, Default)]
+struct CountOptions {
+    // windows with up to this many ambiguous IUPAC bases get fractional counts (see add_ambiguous_windows)
+    max_ambiguous: Option<usize>,
+    // skip windows with a soft-masked (lowercase) base
+    skip_masked: bool,
+}
+
+impl CountOptions {
+    // masking is "all" (count lowercase like uppercase) or "skip". Splitting them into their own profile is find_masked_counts.
+    fn new(max_ambiguous: Option<usize>, masking: &str) -> PyResult<Self> {
+        let skip_masked = match masking {
+            "all" => false,
+            "skip" => true,
+            "separate" => return Err(PyValueError::new_err("masking = \"separate\" gives two profiles, use find_masked_counts")),
+            _ => return Err(PyValueError::new_err(format!("unknown masking {:?}, expected \"all\" or \"skip\"", masking))),
+        };
+        if let Some(m) = max_ambiguous.filter(|&m| m > MAX_AMBIGUOUS) {
+            return Err(PyValueError::new_err(format!("max_ambiguous is {}, it can be at most {}", m, MAX_AMBIGUOUS)));
         }
+        Ok(CountOptions { max_ambiguous, skip_masked })
+    }
 
+    // Lowercase bases become something that is neither a base nor an IUPAC code when skipping, so the counters drop every window with one.
+    #[inline(always)]
+    fn mask(&self, b: u8) -> u8 {
+        if self.skip_masked && b.is_ascii_lowercase() { b'-' } else { b }
     }
+}
 
-    
-    match kmer_to_16_bit_ray::<4>(&fmer[1..5]) {
-        Some(f) => {
-            let class = FOURMERTABLE[f as usize];
-            fourmer_counts[class as usize] += 1;
-        }
-        None => (),
+// Fraction of an IUPAC base that goes to each code of `encode` (base2number or base2number_ry), e.g. Y is half C and
+// half T, so [0, 0.5, 0.5, 0] for ACGT, or [0, 1, 0, 0] for RY. None for anything that isn't an IUPAC base.
+fn iupac_weights(c: u8, encode: fn(u8) -> Option<u8>) -> Option<[f64; 4]> {
+    let bases: &[u8] = match c.to_ascii_uppercase() {
+        b'A' => b"A",
+        b'C' => b"C",
+        b'G' => b"G",
+        b'T' | b'U' => b"T",
+        b'R' => b"AG",
+        b'Y' => b"CT",
+        b'S' => b"CG",
+        b'W' => b"AT",
+        b'K' => b"GT",
+        b'M' => b"AC",
+        b'B' => b"CGT",
+        b'D' => b"AGT",
+        b'H' => b"ACT",
+        b'V' => b"ACG",
+        b'N' => b"ACGT",
+        _ => return None,
+    };
+    let mut weights = [0.0; 4];
+    for &b in bases {
+        weights[encode(b).unwrap() as usize] += 1.0 / bases.len() as f64;
     }
+    Some(weights)
+}
 
-    for i in 1..3 {
-        match kmer_to_16_bit_ray::<3>(&fmer[i..3+i]) {
-            Some(f) => {
-                let class = THREEMERTABLE[f as usize];
-                threemer_counts[class as usize] += 1;
-            }
-            None => (),
-        }
+// The iupac_weights of every base of a contig and how the exact counters see it, worked out once per contig and encoding
+// for all the k add_ambiguous_windows is called with.
+struct AmbiguousBases {
+    weights: Vec<Option<[f64; 4]>>,
+    // 0: a base the exact counter knows, 1: ambiguous, 2: not an IUPAC base, 3: one code, but not one the exact counter knows
+    classes: Vec<u8>,
+}
+
+impl AmbiguousBases {
+    fn new(contig: &[u8], encode: fn(u8) -> Option<u8>) -> Self {
+        let weights = contig.iter().map(|&c| iupac_weights(c, encode)).collect::<Vec<_>>();
+        let classes = contig.iter()
+                            .zip(&weights)
+                            .map(|(&c, w)| match w {
+                                _ if encode(c).is_some() => 0,
+                                Some(w) if w.iter().filter(|&&x| x > 0.).count() == 1 => 3,
+                                Some(_) => 1,
+                                None => 2,
+                            })
+                            .collect::<Vec<u8>>();
+        AmbiguousBases { weights, classes }
     }
-    for i in 1..4 {
-        match kmer_to_16_bit_ray::<2>(&fmer[i..2+i]) {
-            Some(f) => {
-                let class = TWOMERTABLE[f as usize];
-                twomer_counts[class as usize] += 1;
+}
+
+// The exact counters skip every window with a base `encode` (base2number, or base2number_ry with bits = 1) doesn't know.
+// This goes over the first num_windows windows of length k of the contig again, and for the ones with 1 to max_ambiguous
+// such bases (and nothing that isn't an IUPAC base), adds to `out` every kmer the window could be, weighted by how likely
+// it is (product of the iupac_weights), through the canonical table `tbl`. Each such window adds 1 in total. Returns how
+// many windows were added. A window expands to up to 2^(bits * max_ambiguous) kmers, hence MAX_AMBIGUOUS.
+// A code that stands for a single code of `encode` (R and Y for RY, S and W for SW, K and M for KM) isn't ambiguous: it
+// doesn't count towards max_ambiguous, and a window the exact counter skipped only for such codes is added with weight 1.
+fn add_ambiguous_windows(bases: &AmbiguousBases, bits: usize, k: usize, num_windows: usize, max_ambiguous: usize, tbl: &[u32], out: &mut [f64]) -> usize {
+    let AmbiguousBases { weights, classes } = bases;
+    // number of bases of each class in the current window
+    let mut in_window = [0usize; 4];
+    let mut added = 0;
+    // the kmers of the window so far and the ones with the next base, reused for every window
+    let mut kmers: Vec<(u64, f64)> = Vec::new();
+    let mut longer: Vec<(u64, f64)> = Vec::new();
+    for start in 0..num_windows {
+        if start == 0 {
+            for &c in &classes[..k] {
+                in_window[c as usize] += 1;
             }
-            None => (),
+        } else {
+            in_window[classes[start - 1] as usize] -= 1;
+            in_window[classes[start + k - 1] as usize] += 1;
         }
-    }
-    for i in 1..5 {
-        match kmer_to_16_bit_ray::<1>(&fmer[i..1+i]) {
-            Some(f) => {
-                let class = ONEMERTABLE[f as usize];
-                onemer_counts[class as usize] += 1;
+        let [_, ambiguous, invalid, single] = in_window;
+        if invalid > 0 || ambiguous + single == 0 || ambiguous > max_ambiguous {
+            continue;
+        }
+
+        kmers.clear();
+        kmers.push((0, 1.0));
+        for w in &weights[start..start + k] {
+            let w = w.unwrap();
+            longer.clear();
+            for &(code, p) in &kmers {
+                longer.extend((0..1 << bits).filter(|&b| w[b] > 0.).map(|b| ((code << bits) | b as u64, p * w[b])));
             }
-            None => (),
+            std::mem::swap(&mut kmers, &mut longer);
+        }
+        for &(code, p) in &kmers {
+            out[tbl[code as usize] as usize] += p;
         }
+        added += 1;
     }
+    added
+}
 
+// mask of the n lowest bits, n <= 64
+const fn low_bits(n: usize) -> u64 {
+    if n >= 64 { u64::MAX } else { (1u64 << n) - 1 }
+}
 
+// Rolling encoding of the last `max_k` bases of a sequence, so counting doesn't have to re-encode a window for every base and every k.
+//
+// `fwd` holds the bases in the same layout as kmer_to_16_bit_ray (oldest base in the highest bits, `bits` bits per base),
+// `rev` holds the reverse compliment of the same window, and bit i of `invalid` is set if the base i positions back was not
+// a valid base (or hasn't been read yet), so we know which sub windows to skip after an N without restarting anything.
+//
+// `bits` is 2 for ACGT, 1 for the RY alphabet. In both encodings the compliment of a base is its code with the low bit flipped.
+#[derive(Clone, Copy)]
+struct RollingKmer {
+    fwd: u64,
+    rev: u64,
+    invalid: u64,
+    bits: usize,
+    max_k: usize,
+}
 
-    let out_5mer  = norm_vector(fivemer_counts);
-    let out_4mer  = norm_vector(fourmer_counts);
-    let out_3mer  = norm_vector(threemer_counts);
-    let out_2mer = norm_vector(twomer_counts);
-    let out_1mer = norm_vector(onemer_counts);
-
-
-    let mut fourmer_multipliers =  [0f64;136];
-    for i in 0..136 {
-        let mut denom: f64 = 1.0;
-        for _ in 0..MULTIPLIERS[i].0 {
-            denom *= out_1mer[0] as f64;
-           // assert!(onemer_counts[0]  != 0);
+impl RollingKmer {
+    fn new(bits: usize, max_k: usize) -> Self {
+        debug_assert!(bits * max_k <= 64);
+        RollingKmer {
+            fwd: 0,
+            rev: 0,
+            invalid: u64::MAX,
+            bits,
+            max_k,
         }
-        for _ in 0..MULTIPLIERS[i].1 {
-            denom *= out_1mer[1] as f64;
-          //  assert!(onemer_counts[1]  != 0);
+    }
+
+    #[inline(always)]
+    fn push(&mut self, code: Option<u8>) {
+        let (b, bad) = match code {
+            Some(b) => (b as u64, 0),
+            None => (0, 1),
+        };
+        self.fwd = ((self.fwd << self.bits) | b) & low_bits(self.bits * self.max_k);
+        self.rev = (self.rev >> self.bits) | ((b ^ 1) << (self.bits * (self.max_k - 1)));
+        self.invalid = (self.invalid << 1) | bad;
+    }
 
+    /// encoding of the last k bases, None if any of them was invalid
+    #[inline(always)]
+    fn suffix(&self, k: usize) -> Option<u64> {
+        if self.invalid & low_bits(k) != 0 {
+            return None;
         }
-        fourmer_multipliers[i] = denom as f64;
+        Some(self.fwd & low_bits(self.bits * k))
     }
 
-    let mut out_l4n1 = [0.0f32; 136];
+    /// reverse compliment of the last k bases, only meaningful when suffix(k) is Some
+    #[inline(always)]
+    fn suffix_rev(&self, k: usize) -> u64 {
+        self.rev >> (self.bits * (self.max_k - k))
+    }
 
-    for i in 0..136 {
-        let denom = fourmer_multipliers[i];
-        // if denom == 0 and fourmer_counts[i] != 0 {
-        //     panic!();
-        // }
-        out_l4n1[i] = if denom > 0.0 {
-            ((out_4mer[i] as f64)/denom) as f32
-        } else {
-            if out_4mer[i]  > 0.0 {
-                dbg!(&fourmer_counts[i]);
-                dbg!(&fourmer_multipliers[i]);
-                panic!();
-            }
-            0.0
-        };
+    /// encoding of the first k bases of the max_k window, None if any of them was invalid
+    #[inline(always)]
+    fn prefix(&self, k: usize) -> Option<u64> {
+        if (self.invalid >> (self.max_k - k)) & low_bits(k) != 0 {
+            return None;
+        }
+        Some(self.fwd >> (self.bits * (self.max_k - k)))
     }
+}
 
 
-    (out_5mer, out_4mer, out_4mer, out_3mer, out_2mer, out_1mer, invalid_count)
-}
+const fn gen_Nmer_table<const N: usize, const K: usize>(mapping: [(&'static str, usize);N] ) -> [u32; N] {
+    let mut result = [0u32; N];
 
-use numpy::IntoPyArray;
-use std::io::Write;
-use pyo3::Python;
+    let mut i: usize = 0;
+    loop {
+        if i >= N {
+            break;
+        }
+        match kmer_to_16_bit::<K>(mapping[i].0) {
+            Some(x) => {
+                result[x as usize] = mapping[i].1 as u32;
+            } 
+            None => unreachable!(),
+        }
+        i += 1;
+    }
+
+    result
+}
+
+// Canonical tables for arbitrary K. For K <= 5 we hand back the tables built from the *MER_RAY arrays above,
+// so the index order our pretrained models expect is preserved. Anything longer is generated the same way
+// gen_ry_table does it: walk the 2 bit encodings in order, and give each unseen kmer / reverse compliment pair the next index.
+//
+// Tables are 4^K u32s, so K = 12 is already 64MB; they are built on first use and kept around.
+const MAX_CANONICAL_K: usize = 12;
+
+static CANONICAL_TABLES: [std::sync::OnceLock<Vec<u32>>; MAX_CANONICAL_K + 1] = [const { std::sync::OnceLock::new() }; MAX_CANONICAL_K + 1];
+
+// works on K <= 32
+const fn rev_comp_2bit(rep: u64, k: usize) -> u64 {
+    let mut result = 0u64;
+    let mut rep = rep;
+    let mut i = 0;
+    loop {
+        if i >= k {
+            break;
+        }
+        // A <-> T is 0 <-> 1, C <-> G is 2 <-> 3, so complementing a base is flipping its low bit.
+        result = (result << 2) | ((rep & 0b11) ^ 0b01);
+        rep >>= 2;
+        i += 1;
+    }
+    result
+}
+
+/// Number of distinct kmers of length k once a kmer and its reverse compliment are merged.
+const fn num_canonical_kmers(k: usize) -> usize {
+    if k % 2 == 1 {
+        (1 << (2 * k)) / 2
+    } else {
+        ((1 << (2 * k)) + (1 << k)) / 2
+    }
+}
+
+fn gen_canonical_table(k: usize) -> Vec<u32> {
+    let n = 1usize << (2 * k);
+    let mut tbl = vec![u32::MAX; n];
+    let mut map_index = 0;
+    for tbl_idx in 0..n {
+        if tbl[tbl_idx] == u32::MAX {
+            tbl[tbl_idx] = map_index;
+            tbl[rev_comp_2bit(tbl_idx as u64, k) as usize] = map_index;
+            map_index += 1;
+        }
+    }
+    tbl
+}
+
+// the letter of each code in the 2 bit and the RY encoding
+const NUCLEOTIDES: &[u8] = b"ATCG";
+const RY_LETTERS: &[u8] = b"RY";
+
+// For every column of a lookup table, the kmers (in alphabet letters) that are counted in it.
+fn table_labels(tbl: &[u32], k: usize, alphabet: &[u8]) -> Vec<Vec<String>> {
+    let mut labels = vec![Vec::new(); tbl.iter().max().map_or(0, |&m| m as usize + 1)];
+    for (code, &column) in tbl.iter().enumerate() {
+        labels[column as usize].push(kmer_string(code as u64, k, alphabet));
+    }
+    labels
+}
+
+/// Lookup table from the 2*k bit encoding of a kmer to its canonical index, for 1 <= k <= MAX_CANONICAL_K.
+fn canonical_table(k: usize) -> &'static [u32] {
+    match k {
+        1 => &ONEMERTABLE,
+        2 => &TWOMERTABLE,
+        3 => &THREEMERTABLE,
+        4 => &FOURMERTABLE,
+        5 => &FIVEMERTABLE,
+        _ => CANONICAL_TABLES[k].get_or_init(|| gen_canonical_table(k)),
+    }
+}
+
+
+fn norm_vector<const N: usize>(in_vec: [f64;N]) -> [f32; N] {
+    let sum = in_vec.iter()
+                    .sum::<f64>();
+    
+    let mut result =  [0.0; N];
+
+    for i in 0..N {
+        result[i] = (in_vec[i]/sum) as f32;
+    }
+
+    result
+}
+
+
+// same as norm_vector, for tables whose size is only known at run time.
+fn norm_slice(in_vec: &[f64]) -> Vec<f32> {
+    let sum = in_vec.iter()
+                    .sum::<f64>();
+
+    in_vec.iter()
+          .map(|&x| (x/sum) as f32)
+          .collect()
+}
+
+// How a feature block gets turned into the vector we hand to python. A block is either kmer counts, or for the
+// RY 10,9,8,7 mers, the quotiented frequencies from quotient_ry_mers.
+#[derive(Clone, Debug)]
+enum Normalization {
+    L1,
+    L2,
+    // centered log-ratio, with this pseudocount added to every entry first
+    Clr(f64),
+    // normed the block's default way (L1, L2 for the RY quotients, see DEFAULT_NORMS), then standardized entrywise
+    // against a background (mean, std), e.g. computed over a reference set
+    ZScore(Box<Normalization>, Vec<f32>, Vec<f32>),
+    // as is
+    Identity,
+}
+
+// Blocks in output order: 5,4,3,2,1 mers, then RY 10,9,8,7,6 mers.
+const NUM_FEATURE_BLOCKS: usize = 10;
+const FEATURE_BLOCK_SIZES: [usize; NUM_FEATURE_BLOCKS] = [512, 136, 32, 10, 2, 528, 256, 136, 64, 36];
+
+// What we always did: L1 for counts, L2 for the RY quotients.
+const DEFAULT_NORMS: [Normalization; NUM_FEATURE_BLOCKS] = [Normalization::L1, Normalization::L1, Normalization::L1, Normalization::L1, Normalization::L1,
+                                                            Normalization::L2, Normalization::L2, Normalization::L2, Normalization::L2, Normalization::L1];
+
+// Works in f64 the same way norm_vector does (and the L2 norm we used to apply to the RY quotients), so the default norms give bit for bit the same output as before.
+fn normalize<const N: usize>(in_vec: [f64; N], norm: &Normalization) -> [f32; N] {
+    let mut result = [0.0; N];
+    normalize_into(&in_vec, norm, &mut result);
+    result
+}
+
+// normalize, for blocks whose size is only known at run time. result has to be as long as in_vec.
+fn normalize_into(in_vec: &[f64], norm: &Normalization, result: &mut [f32]) {
+    let n = in_vec.len();
+    match norm {
+        Normalization::L1 => {
+            let sum = in_vec.iter().sum::<f64>();
+            for i in 0..n {
+                result[i] = (in_vec[i]/sum) as f32;
+            }
+        },
+        Normalization::L2 => {
+            let sum = in_vec.iter().map(|&x| x*x).sum::<f64>().sqrt();
+            if sum > 0. {
+                for i in 0..n {
+                    result[i] = (in_vec[i]/sum) as f32;
+                }
+            }
+        },
+        Normalization::Clr(pseudocount) => {
+            let logs = in_vec.iter().map(|x| (x + pseudocount).ln()).collect::<Vec<f64>>();
+            let mean = logs.iter().sum::<f64>() / n as f64;
+            for i in 0..n {
+                result[i] = (logs[i] - mean) as f32;
+            }
+        },
+        Normalization::ZScore(base, mean, std) => {
+            normalize_into(in_vec, base, result);
+            for i in 0..n {
+                result[i] = if std[i] > 0. { (result[i] - mean[i]) / std[i] } else { 0.0 };
+            }
+        },
+        Normalization::Identity => {
+            for i in 0..n {
+                result[i] = in_vec[i] as f32;
+            }
+        },
+    }
+}
+
+fn counts_to_f64<const N: usize>(in_vec: [u32; N]) -> [f64; N] {
+    in_vec.map(|x| x as f64)
+}
+
+fn freqs_to_f64<const N: usize>(in_vec: [f32; N]) -> [f64; N] {
+    in_vec.map(|x| x as f64)
+}
+
+/// Per feature block normalization for find_nMer_distributions, find_single, sampling and FastaDataBase.sample.
+/// Build one and pass it to all of them, so training and inference features are computed the same way.
+///
+/// `norms` is one of "l1", "l2", "clr", "zscore" or "none" per block, in output order
+/// (5,4,3,2,1 mers, then RY 10,9,8,7,6 mers). The default is l1 for everything except l2 for RY 10,9,8,7 mers.
+/// "clr" adds `pseudocount` (> 0, so a zero count has a log) to every entry first. "zscore" needs `background_mean` and
+/// `background_std`, one list per block (may be empty for the blocks that don't use zscore), each the size of the block.
+/// It standardizes the block as the default norm gives it: L1 frequencies, or the L2 normed RY 10,9,8,7 mer quotients.
+#[pyclass]
+#[pyo3(name = "Normalization")]
+#[derive(Clone)]
+struct FeatureNormalization {
+    blocks: Vec<Normalization>,
+}
+
+#[pymethods]
+impl FeatureNormalization {
+    #[new]
+    #[args(norms = "None", pseudocount = "0.5", background_mean = "None", background_std = "None")]
+    fn new(norms: Option<Vec<String>>, pseudocount: f64, background_mean: Option<Vec<Vec<f32>>>, background_std: Option<Vec<Vec<f32>>>) -> PyResult<Self> {
+        let norms = match norms {
+            Some(norms) => norms,
+            None => return Ok(Self::default()),
+        };
+        if norms.len() != NUM_FEATURE_BLOCKS {
+            return Err(PyValueError::new_err(format!("expected {} norms, one per feature block, got {}", NUM_FEATURE_BLOCKS, norms.len())));
+        }
+        if pseudocount.is_nan() || pseudocount <= 0.0 {
+            return Err(PyValueError::new_err(format!("pseudocount must be positive, got {}", pseudocount)));
+        }
+
+        let mut blocks = Vec::with_capacity(NUM_FEATURE_BLOCKS);
+        for (i, norm) in norms.iter().enumerate() {
+            blocks.push(match norm.to_lowercase().as_str() {
+                "l1" => Normalization::L1,
+                "l2" => Normalization::L2,
+                "clr" => Normalization::Clr(pseudocount),
+                "none" => Normalization::Identity,
+                "zscore" => {
+                    let background = background_mean.as_ref().zip(background_std.as_ref())
+                                                     .map(|(mean, std)| (mean.get(i), std.get(i)));
+                    match background {
+                        Some((Some(mean), Some(std))) if mean.len() == FEATURE_BLOCK_SIZES[i] && std.len() == FEATURE_BLOCK_SIZES[i] => {
+                            Normalization::ZScore(Box::new(DEFAULT_NORMS[i].clone()), mean.clone(), std.clone())
+                        },
+                        _ => return Err(PyValueError::new_err(format!("zscore for block {} needs background_mean and background_std of length {}", i, FEATURE_BLOCK_SIZES[i]))),
+                    }
+                },
+                other => return Err(PyValueError::new_err(format!("unknown normalization '{}', expected one of l1, l2, clr, zscore, none", other))),
+            });
+        }
+        Ok(Self { blocks })
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Normalization({:?})", self.blocks)
+    }
+}
+
+impl Default for FeatureNormalization {
+    fn default() -> Self {
+        Self { blocks: DEFAULT_NORMS.to_vec() }
+    }
+}
+
+// Python side argument -> the norms to use, defaulting to DEFAULT_NORMS.
+fn feature_norms(norm: Option<PyRef<FeatureNormalization>>) -> Vec<Normalization> {
+    norm.map(|x| x.blocks.clone()).unwrap_or_else(|| DEFAULT_NORMS.to_vec())
+}
+
+const MAX_SKETCH_K: usize = 32;
+
+// murmur3's 64 bit finalizer, so the canonical kmer codes are spread evenly over u64 (and AAA...A isn't always 0)
+const fn hash_kmer(code: u64) -> u64 {
+    let mut h = code ^ 0x9e37_79b9_7f4a_7c15;
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^= h >> 33;
+    h
+}
+
+// Pushes the hash of every canonical (min of the kmer and its reverse compliment) k mer of the contig below max_hash.
+fn sketch_hashes(contig: &[u8], k: usize, max_hash: u64, out: &mut Vec<u64>) {
+    let mut window = RollingKmer::new(2, k);
+    for &b in contig {
+        window.push(base2number(b));
+        if let Some(f) = window.suffix(k) {
+            let h = hash_kmer(f.min(window.suffix_rev(k)));
+            if h <= max_hash {
+                out.push(h);
+            }
+        }
+    }
+}
+
+/// A FracMinHash sketch: the hashes of the canonical k mers that fall below u64::MAX / scaled, so about one in `scaled`
+/// distinct k mers. Sketches with the same k can be compared; if scaled differs the finer one is downsampled.
+#[pyclass]
+#[pyo3(name = "Sketch")]
+#[derive(Clone)]
+struct Sketch {
+    #[pyo3(get)]
+    k: usize,
+    #[pyo3(get)]
+    scaled: u64,
+    // sorted, no duplicates
+    hashes: Vec<u64>,
+}
+
+impl Sketch {
+    fn from_contigs(contigs: impl Iterator<Item = impl AsRef<[u8]>>, k: usize, scaled: u64) -> Self {
+        let max_hash = u64::MAX / scaled;
+        let mut hashes = Vec::new();
+        for contig in contigs {
+            sketch_hashes(contig.as_ref(), k, max_hash, &mut hashes);
+        }
+        hashes.sort_unstable();
+        hashes.dedup();
+        Sketch { k, scaled, hashes }
+    }
+
+    // (size of self, size of other, size of the intersection), after downsampling both to the larger scaled
+    fn overlap(&self, other: &Sketch) -> (usize, usize, usize) {
+        let max_hash = u64::MAX / self.scaled.max(other.scaled);
+        let a = &self.hashes[..self.hashes.partition_point(|&h| h <= max_hash)];
+        let b = &other.hashes[..other.hashes.partition_point(|&h| h <= max_hash)];
+
+        let (mut i, mut j, mut common) = (0, 0, 0);
+        while i < a.len() && j < b.len() {
+            match a[i].cmp(&b[j]) {
+                std::cmp::Ordering::Less => i += 1,
+                std::cmp::Ordering::Greater => j += 1,
+                std::cmp::Ordering::Equal => {
+                    common += 1;
+                    i += 1;
+                    j += 1;
+                },
+            }
+        }
+        (a.len(), b.len(), common)
+    }
+
+    fn jaccard_unch(&self, other: &Sketch) -> f64 {
+        let (a, b, common) = self.overlap(other);
+        if a + b > common { common as f64 / (a + b - common) as f64 } else { 0.0 }
+    }
+
+    fn containment_unch(&self, other: &Sketch) -> f64 {
+        let (a, _, common) = self.overlap(other);
+        if a > 0 { common as f64 / a as f64 } else { 0.0 }
+    }
+
+    // point estimate of ANI from containment: a k mer is shared when all k of its bases are, so C ~ ANI^k
+    fn ani_unch(&self, other: &Sketch) -> f64 {
+        self.containment_unch(other).powf(1.0 / self.k as f64)
+    }
+
+    fn check_comparable(&self, other: &Sketch) -> PyResult<()> {
+        if self.k != other.k {
+            return Err(PyValueError::new_err(format!("can't compare sketches with k = {} and k = {}", self.k, other.k)));
+        }
+        Ok(())
+    }
+}
+
+#[pymethods]
+impl Sketch {
+    /// The hashes in the sketch, sorted.
+    #[getter]
+    fn hashes<'py>(&self, py: Python<'py>) -> &'py PyArray1<u64> {
+        PyArray::from_slice(py, &self.hashes)
+    }
+
+    /// Jaccard similarity of the two sketches.
+    fn jaccard(&self, other: PyRef<Sketch>) -> PyResult<f64> {
+        self.check_comparable(&other)?;
+        Ok(self.jaccard_unch(&other))
+    }
+
+    /// Fraction of this sketch's k mers that are in other.
+    fn containment(&self, other: PyRef<Sketch>) -> PyResult<f64> {
+        self.check_comparable(&other)?;
+        Ok(self.containment_unch(&other))
+    }
+
+    /// ANI of this sketch's sequence to other, estimated from containment (containment ** (1 / k)).
+    fn ani(&self, other: PyRef<Sketch>) -> PyResult<f64> {
+        self.check_comparable(&other)?;
+        Ok(self.ani_unch(&other))
+    }
+
+    fn __len__(&self) -> usize {
+        self.hashes.len()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Sketch(k={}, scaled={}, hashes={})", self.k, self.scaled, self.hashes.len())
+    }
+}
+
+// metric of every query against every reference, row major (one row per query)
+fn sketch_matrix(queries: &[Sketch], references: &[Sketch], metric: fn(&Sketch, &Sketch) -> f64) -> Vec<f64> {
+    queries.par_iter()
+           .flat_map_iter(|q| references.iter().map(move |r| metric(q, r)))
+           .collect()
+}
+
+const MAX_LONG_K: usize = 31;
+// long contigs are counted in chunks of this many k mers, so a single genome is still counted in parallel
+const LONG_KMER_CHUNK: usize = 1 << 20;
+
+fn merge_counts(a: HashMap<u64, u32>, b: HashMap<u64, u32>) -> HashMap<u64, u32> {
+    let (mut big, small) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    for (kmer, count) in small {
+        let c = big.entry(kmer).or_insert(0);
+        *c = c.saturating_add(count);
+    }
+    big
+}
+
+// canonical k mer -> number of occurrences, counted chunk (LONG_KMER_CHUNK) k mers at a time
+fn count_long_kmers(seqs: &[Vec<u8>], k: usize, chunk: usize) -> HashMap<u64, u32> {
+    seqs.par_iter()
+        .flat_map_iter(|seq| {
+            (0..seq.len().saturating_sub(k - 1)).step_by(chunk)
+                                                 .map(move |start| &seq[start..seq.len().min(start + chunk + k - 1)])
+        })
+        .fold(HashMap::new, |mut counts, chunk| {
+            let mut window = RollingKmer::new(2, k);
+            for &b in chunk {
+                window.push(base2number(b));
+                if let Some(f) = window.suffix(k) {
+                    let c: &mut u32 = counts.entry(f.min(window.suffix_rev(k))).or_insert(0);
+                    *c = c.saturating_add(1);
+                }
+            }
+            counts
+        })
+        .reduce(HashMap::new, merge_counts)
+}
+
+// KmerSpectrum reads this many bytes of sequence at a time, so a read set doesn't have to fit in memory
+const SPECTRUM_BATCH_SIZE: usize = 1 << 28;
+
+// count_long_kmers of every sequence of at least min_len bases of a fasta or fastq from reader, batch_size bytes at a
+// time (see for_each_record_batch). Low quality fastq bases are masked with min_quality (see mask_low_quality).
+fn count_reader_kmers(reader: &mut impl BufRead, k: usize, min_len: usize, min_quality: Option<u8>, batch_size: usize) -> std::io::Result<HashMap<u64, u32>> {
+    let mut counts = HashMap::new();
+    for_each_record_batch(reader, batch_size, min_quality, |records| {
+        let sequences = records.par_iter().map(|record| record.sequence()).filter(|seq| seq.len() >= min_len).collect::<Vec<_>>();
+        counts = merge_counts(std::mem::take(&mut counts), count_long_kmers(&sequences, k, LONG_KMER_CHUNK));
+    })?;
+    Ok(counts)
+}
+
+// result[c] = number of distinct k mers seen c times, k mers seen max_count or more times all go in the last bin
+fn spectrum(counts: &HashMap<u64, u32>, max_count: Option<u32>) -> Vec<u64> {
+    let max_count = max_count.unwrap_or_else(|| counts.values().copied().max().unwrap_or(0)) as usize;
+    let mut hist = vec![0u64; max_count + 1];
+    for &c in counts.values() {
+        hist[(c as usize).min(max_count)] += 1;
+    }
+    hist
+}
+
+fn kmer_string(code: u64, k: usize, alphabet: &[u8]) -> String {
+    let bits = alphabet.len().trailing_zeros() as usize;
+    (0..k).rev().map(|i| alphabet[((code >> (bits * i)) & low_bits(bits)) as usize] as char).collect()
+}
+
+/// Exact counts of canonical k mers (k <= 31) over one or more fasta or fastq files, to get k mer spectra
+/// (genome size, coverage, contamination) before genomes go into a FastaDataBase.
+#[pyclass]
+#[pyo3(name = "KmerSpectrum")]
+struct KmerSpectrum {
+    #[pyo3(get)]
+    k: usize,
+    counts: HashMap<u64, u32>,
+}
+
+impl KmerSpectrum {
+    // (k mer, count) for all k mers seen at least min_count times, sorted by k mer
+    fn above(&self, min_count: u32) -> Vec<(u64, u32)> {
+        let mut kmers = self.counts.par_iter()
+                                   .filter(|(_, &c)| c >= min_count)
+                                   .map(|(&kmer, &c)| (kmer, c))
+                                   .collect::<Vec<_>>();
+        kmers.par_sort_unstable();
+        kmers
+    }
+}
+
+#[pymethods]
+impl KmerSpectrum {
+    /// Counts the canonical k mers of all sequences of at least min_len bases in the (optionally compressed) fasta/fastq files.
+    /// k mers with a base other than ACGT are skipped; min_quality = q masks fastq bases with a Phred quality below q.
+    /// Files are streamed, so they don't have to fit in memory.
+    #[new]
+    #[args(k = "31", min_len = "0", min_quality = "None")]
+    fn new(py: Python, files: Vec<String>, k: usize, min_len: usize, min_quality: Option<u8>) -> PyResult<Self> {
+        if k == 0 || k > MAX_LONG_K {
+            return Err(PyValueError::new_err(format!("k must be between 1 and {}, got {}", MAX_LONG_K, k)));
+        }
+        let counts = py.allow_threads(|| {
+            files.iter().try_fold(HashMap::new(), |counts, file| {
+                let file_counts = open_contig_file(file).and_then(|mut reader| count_reader_kmers(&mut reader, k, min_len, min_quality, SPECTRUM_BATCH_SIZE))
+                                                        .map_err(|e| contig_file_error(file, e))?;
+                Ok::<_, PyErr>(merge_counts(counts, file_counts))
+            })
+        })?;
+        Ok(KmerSpectrum { k, counts })
+    }
+
+    /// Total number of k mers counted.
+    #[getter]
+    fn total(&self) -> u64 {
+        self.counts.values().map(|&c| c as u64).sum()
+    }
+
+    /// Abundance histogram: hist[c] is the number of distinct k mers seen c times. With max_count, k mers seen
+    /// max_count or more times are all in hist[max_count].
+    #[args(max_count = "None")]
+    fn histogram<'py>(&self, py: Python<'py>, max_count: Option<u32>) -> &'py PyArray1<u64> {
+        spectrum(&self.counts, max_count).into_pyarray(py)
+    }
+
+    /// (kmers, counts) of all canonical k mers seen at least min_count times, sorted.
+    #[args(min_count = "2")]
+    fn dump<'py>(&self, py: Python<'py>, min_count: u32) -> (Vec<String>, &'py PyArray1<u32>) {
+        let kmers = py.allow_threads(|| self.above(min_count));
+        let k = self.k;
+        (kmers.iter().map(|&(kmer, _)| kmer_string(kmer, k, NUCLEOTIDES)).collect(), kmers.iter().map(|&(_, c)| c).collect::<Vec<u32>>().into_pyarray(py))
+    }
+
+    /// Same as dump, but writes "kmer\tcount" lines to path.
+    #[args(min_count = "2")]
+    fn write_dump(&self, py: Python, path: &str, min_count: u32) -> PyResult<()> {
+        py.allow_threads(|| {
+            let mut out = std::io::BufWriter::new(File::create(path)?);
+            for (kmer, c) in self.above(min_count) {
+                writeln!(out, "{}\t{}", kmer_string(kmer, self.k, NUCLEOTIDES), c)?;
+            }
+            out.flush()
+        })?;
+        Ok(())
+    }
+
+    fn __len__(&self) -> usize {
+        self.counts.len()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("KmerSpectrum(k={}, distinct={}, total={})", self.k, self.counts.len(), self.total())
+    }
+}
+
+fn check_sketch_args(k: usize, scaled: u64) -> PyResult<()> {
+    if k == 0 || k > MAX_SKETCH_K {
+        return Err(PyValueError::new_err(format!("k must be between 1 and {}, got {}", MAX_SKETCH_K, k)));
+    }
+    if scaled == 0 {
+        return Err(PyValueError::new_err("scaled must be positive"));
+    }
+    Ok(())
+}
+
+
+
+fn contig_to_labeled(contig: &[u8]) -> [u32;2000] {
+    let mut result = [0u32;2000];
+    
+    for (idx, chunk) in contig.windows(5).enumerate() {
+        result[idx] = kmer_to_16_bit_ray::<5>(chunk).unwrap_or_else( || 1024) as u32;
+    }
+    result
+}
+
+
+fn contig_to_labeled_flip(contig: &[u8],flip: bool) -> [u32;2000] {
+    let mut result = [0u32;2000];
+
+    // let max = if contig.len() < 2000 { contig.len() } else { 2000 };
+    if flip {
+        let window_size = 5;
+        for (idx, i) in (window_size..=contig.len()).rev().enumerate() {
+            let window = &contig[i - window_size..i];
+            result[idx] = kmer_to_16_bit_ray_revcomp::<5>(window).unwrap_or_else( || 1024) as u32;
+          }
+    } else {
+
+        for (idx, chunk) in contig.windows(5).enumerate() {
+            result[idx] = kmer_to_16_bit_ray::<5>(chunk).unwrap_or_else( || 1024) as u32;
+        }   
+    }
+    result
+    
+}
+
+
+fn contig_to_labeled2(contig: &[u8]) -> [u32;2000] {
+    let mut result = [0u32;2000];
+    // let max = if contig.len() < 2000 { contig.len() } else { 2000 };
+    for (idx, chunk) in contig.chunks(1).enumerate() {
+        result[idx] = kmer_to_16_bit_ray::<1>(chunk).unwrap_or_else( || 4) as u32;
+    }
+    result
+}
+
+
+
+
+// 5,4,3,2,1 mer counts and the number of invalid 5 base windows
+type NmerCounts = ([u32; 512], [u32; 136], [u32; 32], [u32; 10], [u32; 2], usize);
+// NmerCounts as f64, so windows with ambiguous bases can add fractional counts (see add_ambiguous_nmers)
+type FracNmerCounts = ([f64; 512], [f64; 136], [f64; 32], [f64; 10], [f64; 2], usize);
+// 5,4,4,3,2,1 mer distributions and the number of invalid 5 base windows. The second 4 mer is the slot our models
+// call l4n1; it has always held plain 4 mers, see markov_ratios for the real thing.
+type NmerDistrs = ([f32; 512], [f32; 136], [f32; 136], [f32; 32], [f32; 10], [f32; 2], usize);
+
+// Counts every canonical 5,4,3,2,1 mer in the contig, plus the number of 5 base windows that had a non ACGT base.
+fn nmer_counts(contig: impl Iterator<Item = u8>) -> NmerCounts {
+    let mut onemer_counts = [0u32; 2];
+    let mut twomer_counts = [0u32; 10];
+    let mut threemer_counts = [0u32; 32];
+    let mut fourmer_counts = [0u32; 136];
+    let mut fivemer_counts = [0u32; 512];
+
+    let mut invalid_count = 0;
+
+    let mut window = RollingKmer::new(2, 5);
+
+    for (pos, b) in contig.enumerate() {
+        window.push(base2number(b));
+
+        match window.suffix(5) {
+            Some(f) => fivemer_counts[FIVEMERTABLE[f as usize] as usize] += 1,
+            None => {
+                if pos >= 4 {
+                    invalid_count += 1;
+                }
+            },
+        }
+        if let Some(f) = window.suffix(4) {
+            fourmer_counts[FOURMERTABLE[f as usize] as usize] += 1;
+        }
+        if let Some(f) = window.suffix(3) {
+            threemer_counts[THREEMERTABLE[f as usize] as usize] += 1;
+        }
+        if let Some(f) = window.suffix(2) {
+            twomer_counts[TWOMERTABLE[f as usize] as usize] += 1;
+        }
+        if let Some(f) = window.suffix(1) {
+            onemer_counts[ONEMERTABLE[f as usize] as usize] += 1;
+        }
+    }
+
+    (fivemer_counts, fourmer_counts, threemer_counts, twomer_counts, onemer_counts, invalid_count)
+}
+
+// norms: the 5,4,3,2,1 mer entries of a FeatureNormalization
+fn nmer_distrs_from_counts(counts: FracNmerCounts, norms: &[Normalization]) -> NmerDistrs {
+    let (fivemer_counts, fourmer_counts, threemer_counts, twomer_counts, onemer_counts, invalid_count) = counts;
+
+    let out_5mer = normalize(fivemer_counts, &norms[0]);
+    let out_4mer = normalize(fourmer_counts, &norms[1]);
+    let out_3mer = normalize(threemer_counts, &norms[2]);
+    let out_2mer = normalize(twomer_counts, &norms[3]);
+    let out_1mer = normalize(onemer_counts, &norms[4]);
+
+    (out_5mer, out_4mer, out_4mer, out_3mer, out_2mer, out_1mer, invalid_count)
+}
+
+fn frac_nmer_counts(counts: NmerCounts) -> FracNmerCounts {
+    (counts_to_f64(counts.0), counts_to_f64(counts.1), counts_to_f64(counts.2), counts_to_f64(counts.3), counts_to_f64(counts.4), counts.5)
+}
+
+// Adds the windows with 1 to max_ambiguous ambiguous bases to counts (see add_ambiguous_windows).
+fn add_ambiguous_nmers(contig: &[u8], max_ambiguous: usize, counts: &mut FracNmerCounts) {
+    let num_windows = |k: usize| (contig.len() + 1).saturating_sub(k);
+    let bases = AmbiguousBases::new(contig, base2number);
+    counts.5 -= add_ambiguous_windows(&bases, 2, 5, num_windows(5), max_ambiguous, &FIVEMERTABLE, &mut counts.0);
+    add_ambiguous_windows(&bases, 2, 4, num_windows(4), max_ambiguous, &FOURMERTABLE, &mut counts.1);
+    add_ambiguous_windows(&bases, 2, 3, num_windows(3), max_ambiguous, &THREEMERTABLE, &mut counts.2);
+    add_ambiguous_windows(&bases, 2, 2, num_windows(2), max_ambiguous, &TWOMERTABLE, &mut counts.3);
+    add_ambiguous_windows(&bases, 2, 1, num_windows(1), max_ambiguous, &ONEMERTABLE, &mut counts.4);
+}
+
+fn contig_2_frac_nmer_counts_bytes(contig: &[u8], opts: CountOptions) -> FracNmerCounts {
+    let bases = contig.iter().map(|&b| opts.mask(b));
+    let mut counts = frac_nmer_counts(nmer_counts(bases.clone()));
+    if let Some(max_ambiguous) = opts.max_ambiguous {
+        add_ambiguous_nmers(&bases.collect::<Vec<u8>>(), max_ambiguous, &mut counts);
+    }
+    counts
+}
+
+fn add_counts<const N: usize>(a: &mut [f64; N], b: &[f64; N]) {
+    a.iter_mut().zip(b).for_each(|(x, y)| *x += y);
+}
+
+// Summed 5,4,3,2,1 mer and RY 10,9,8,7,6 mer counts of a group of contigs (see find_group_distributions)
+type GroupCounts = (FracNmerCounts, FracRymerCounts);
+
+fn empty_group_counts() -> GroupCounts {
+    (([0.0; 512], [0.0; 136], [0.0; 32], [0.0; 10], [0.0; 2], 0), ([0.0; 528], [0.0; 256], [0.0; 136], [0.0; 64], [0.0; 36], 0))
+}
+
+fn merge_group_counts(mut a: GroupCounts, b: GroupCounts) -> GroupCounts {
+    add_counts(&mut a.0.0, &b.0.0);
+    add_counts(&mut a.0.1, &b.0.1);
+    add_counts(&mut a.0.2, &b.0.2);
+    add_counts(&mut a.0.3, &b.0.3);
+    add_counts(&mut a.0.4, &b.0.4);
+    a.0.5 += b.0.5;
+    add_counts(&mut a.1.0, &b.1.0);
+    add_counts(&mut a.1.1, &b.1.1);
+    add_counts(&mut a.1.2, &b.1.2);
+    add_counts(&mut a.1.3, &b.1.3);
+    add_counts(&mut a.1.4, &b.1.4);
+    a.1.5 += b.1.5;
+    a
+}
+
+// The counts of the contigs are added up, not the contigs concatenated, so no kmers are made up across contig ends.
+fn group_counts(contigs: &[&[u8]], opts: CountOptions) -> GroupCounts {
+    contigs.par_iter()
+           .map(|ctg| (contig_2_frac_nmer_counts_bytes(ctg, opts), contig_2_frac_rymer_counts_bytes(ctg, opts)))
+           .reduce(empty_group_counts, merge_group_counts)
+}
+
+// The pooled profile of each group: the summed counts normalized, not the mean of the contigs' profiles.
+fn group_distrs(groups: &[&Vec<&[u8]>], norms: &[Normalization], opts: CountOptions) -> Vec<(NmerDistrs, RymerDistrs)> {
+    groups.par_iter()
+          .map(|ctgs| {
+              let (nmers, rymers) = group_counts(ctgs, opts);
+              (nmer_distrs_from_counts(nmers, &norms[..5]), rymer_distrs_from_counts(rymers, &norms[5..]))
+          })
+          .collect()
+}
+
+// What find_nMer_distributions returns for some fasta records: contig_lens, the flattened blocks (the 10 default
+// ones, then the ExtraBlocks asked for) and contig_names.
+struct NmerBatch {
+    contig_lens: Vec<usize>,
+    blocks: Vec<Vec<f32>>,
+    contig_names: Vec<String>,
+}
+
+impl NmerBatch {
+    fn new(num_blocks: usize) -> Self {
+        NmerBatch { contig_lens: Vec::new(), blocks: vec![Vec::new(); num_blocks], contig_names: Vec::new() }
+    }
+
+    fn append(&mut self, other: NmerBatch) {
+        self.contig_lens.extend(other.contig_lens);
+        self.blocks.iter_mut().zip(other.blocks).for_each(|(block, more)| block.extend(more));
+        self.contig_names.extend(other.contig_names);
+    }
+}
+
+// The optional blocks of find_nMer_distributions, in the order they come after the 10 default ones.
+#[derive(Default)]
+struct ExtraBlocks {
+    // (k, order) fills the 4 mer block with markov_ratios instead, like sample does, so it adds no block
+    markov: Option<(usize, usize)>,
+    quotients: bool,
+    seeds: Vec<SpacedSeed>,
+    // Some(homopolymer_len) adds the NUM_CONTIG_STATS columns of contig_stats, so the file isn't read again for them
+    contig_stats: Option<usize>,
+    // adds the lowercase_fraction of every contig
+    masked_fraction: bool,
+}
+
+impl ExtraBlocks {
+    fn num_blocks(&self) -> usize {
+        self.seeds.len() + if self.quotients { 4 } else { 0 } + self.contig_stats.is_some() as usize + self.masked_fraction as usize
+    }
+}
+
+// Counts the records with at least mini_size bases. Each contig is counted in one go, with its line breaks removed.
+fn nmer_batch(records: &[FastaRecord], mini_size: usize, norms: &[Normalization], opts: CountOptions, extra: &ExtraBlocks) -> NmerBatch {
+    let contigs = records.par_iter()
+                         .filter_map(|record| {
+                             let ctg = record.sequence();
+                             if ctg.len() < mini_size {
+                                 return None;
+                             }
+                             let counts = contig_2_frac_nmer_counts_bytes(&ctg, opts);
+                             Some((ctg.len(), record.name(), nmer_distrs_from_counts(counts, &norms[..5]), find_rymers_bytes(&ctg, &norms[5..], opts),
+                                   extra.quotients.then(|| nmer_quotients(&counts)), extra.seeds.iter().map(|seed| seed_distr(&ctg, seed, opts)).collect::<Vec<_>>(),
+                                   extra.contig_stats.map(|homopolymer_len| contig_stats(&ctg, homopolymer_len)), extra.masked_fraction.then(|| lowercase_fraction(&ctg)),
+                                   extra.markov.map(|(k, order)| markov_ratios(ctg.iter().copied(), k, order))))
+                         })
+                         .collect::<Vec<_>>();
+
+    let mut blocks: Vec<Vec<f32>> = vec![
+        contigs.par_iter().flat_map_iter(|c| c.2.0).collect(),
+        contigs.par_iter()
+               .flat_map_iter(|c| match &c.8 {
+                   Some(ratios) => ratios.clone(),
+                   None => c.2.2.to_vec(),
+               })
+               .collect(),
+        contigs.par_iter().flat_map_iter(|c| c.2.3).collect(),
+        contigs.par_iter().flat_map_iter(|c| c.2.4).collect(),
+        contigs.par_iter().flat_map_iter(|c| c.2.5).collect(),
+        contigs.par_iter().flat_map_iter(|c| c.3.0).collect(),
+        contigs.par_iter().flat_map_iter(|c| c.3.1).collect(),
+        contigs.par_iter().flat_map_iter(|c| c.3.2).collect(),
+        contigs.par_iter().flat_map_iter(|c| c.3.3).collect(),
+        contigs.par_iter().flat_map_iter(|c| c.3.4).collect(),
+    ];
+    if extra.quotients {
+        blocks.extend(quotient_blocks(&contigs.iter().filter_map(|c| c.4).collect::<Vec<_>>()));
+    }
+    blocks.extend((0..extra.seeds.len()).map(|s| contigs.par_iter().flat_map_iter(|c| c.5[s].iter().copied()).collect::<Vec<f32>>()));
+    if extra.contig_stats.is_some() {
+        blocks.push(contigs.par_iter().flat_map_iter(|c| c.6.unwrap()).collect());
+    }
+    if extra.masked_fraction {
+        blocks.push(contigs.iter().filter_map(|c| c.7).collect());
+    }
+
+    NmerBatch {
+        contig_lens: contigs.iter().map(|c| c.0).collect(),
+        blocks,
+        contig_names: contigs.into_iter().map(|c| c.1).collect(),
+    }
+}
+
+// nmer_batch of every record read from reader, counted batch_size bytes of records at a time (see for_each_record_batch).
+// The output doesn't depend on batch_size.
+fn nmer_distributions(reader: &mut impl BufRead, batch_size: usize, min_quality: Option<u8>, mini_size: usize, norms: &[Normalization], opts: CountOptions, extra: &ExtraBlocks) -> std::io::Result<NmerBatch> {
+    let mut batch = NmerBatch::new(10 + extra.num_blocks());
+    for_each_record_batch(reader, batch_size, min_quality, |records| {
+        batch.append(nmer_batch(records, mini_size, norms, opts, extra));
+    })?;
+    Ok(batch)
+}
+
+fn contig_2_nmer_distrs_bytes(contig: &[u8], norms: &[Normalization], opts: CountOptions) -> NmerDistrs {
+    nmer_distrs_from_counts(contig_2_frac_nmer_counts_bytes(contig, opts), norms)
+}
+
+// quotiented canonical 5,4,3,2 mer distributions, see nmer_quotients
+type NmerQuotients = ([f32; 512], [f32; 136], [f32; 32], [f32; 10]);
+
+// The RY quotients for the canonical nucleotide kmers: each 5,4,3,2 mer frequency divided by the product of the frequencies
+// of its two overlapping k-1 mers, then L2 normed like the RY quotients. Only products of 0 are skipped.
+fn nmer_quotients(counts: &FracNmerCounts) -> NmerQuotients {
+    let f5 = normalize(counts.0, &Normalization::L1);
+    let f4 = normalize(counts.1, &Normalization::L1);
+    let f3 = normalize(counts.2, &Normalization::L1);
+    let f2 = normalize(counts.3, &Normalization::L1);
+    let f1 = normalize(counts.4, &Normalization::L1);
+
+    let (mut q5, mut q4, mut q3, mut q2) = (f5, f4, f3, f2);
+    quotient_mers(&mut q5, &f4, &FIVEMERTABLE, &FOURMERTABLE, 5, 2, 0.0);
+    quotient_mers(&mut q4, &f3, &FOURMERTABLE, &THREEMERTABLE, 4, 2, 0.0);
+    quotient_mers(&mut q3, &f2, &THREEMERTABLE, &TWOMERTABLE, 3, 2, 0.0);
+    quotient_mers(&mut q2, &f1, &TWOMERTABLE, &ONEMERTABLE, 2, 2, 0.0);
+
+    (normalize(freqs_to_f64(q5), &Normalization::L2), normalize(freqs_to_f64(q4), &Normalization::L2),
+     normalize(freqs_to_f64(q3), &Normalization::L2), normalize(freqs_to_f64(q2), &Normalization::L2))
+}
+
+// Windows of a spaced seed like "11011" can be up to this long (so a window fits a RollingKmer)
+const MAX_SEED_SPAN: usize = 32;
+
+// A spaced seed: the 1s of a mask like "11011" or "1101011" pick the positions of each window that make up the kmer
+// (of length weight), the 0s are ignored, so a point mutation or an N there doesn't change or drop the kmer.
+#[derive(Clone, Debug)]
+struct SpacedSeed {
+    span: usize,
+    // positions of the 1s, 0 is the first base of the window
+    care: Vec<usize>,
+    // the bits of RollingKmer.invalid that belong to care positions, on the forward strand and mirrored for the reverse one
+    care_mask: u64,
+    rev_care_mask: u64,
+    // the mask reads the same backwards, so the seed on the other strand is the reverse compliment of the seed
+    symmetric: bool,
+}
+
+impl SpacedSeed {
+    fn new(mask: &str) -> PyResult<Self> {
+        Self::parse(mask).ok_or_else(|| PyValueError::new_err(format!("seed {:?} should be a string of 0s and 1s, at most {} long with 1 to {} 1s",
+                                                                      mask, MAX_SEED_SPAN, MAX_CANONICAL_K)))
+    }
+
+    // None if the mask isn't a valid seed
+    fn parse(mask: &str) -> Option<Self> {
+        let span = mask.len();
+        let care = mask.bytes().enumerate().filter(|&(_, c)| c == b'1').map(|(i, _)| i).collect::<Vec<_>>();
+        if !mask.bytes().all(|c| c == b'0' || c == b'1') || care.is_empty() || span > MAX_SEED_SPAN || care.len() > MAX_CANONICAL_K {
+            return None;
+        }
+        let care_mask = care.iter().fold(0u64, |m, &i| m | 1 << (span - 1 - i));
+        let rev_care_mask = care.iter().fold(0u64, |m, &i| m | 1 << i);
+        let symmetric = mask.bytes().eq(mask.bytes().rev());
+        Some(SpacedSeed { span, care, care_mask, rev_care_mask, symmetric })
+    }
+
+    fn weight(&self) -> usize {
+        self.care.len()
+    }
+
+    // the 2 bit code of the care positions of a span long window
+    #[inline(always)]
+    fn extract(&self, window: u64) -> u64 {
+        self.care.iter().fold(0, |code, &i| (code << 2) | ((window >> (2 * (self.span - 1 - i))) & 3))
+    }
+}
+
+fn check_seeds(seeds: Option<Vec<String>>) -> PyResult<Vec<SpacedSeed>> {
+    seeds.unwrap_or_default().iter().map(|mask| SpacedSeed::new(mask)).collect()
+}
+
+// L1 normed distribution of the canonical spaced kmers of the contig, in the columns of canonical_table(seed.weight()).
+// A window only needs ACGT at the care positions. For an asymmetric seed the seed on the reverse strand is a different set
+// of positions (with its own care_mask), so both strands are counted to keep the profile strand independent.
+fn seed_distr(contig: &[u8], seed: &SpacedSeed, opts: CountOptions) -> Vec<f32> {
+    let tbl = canonical_table(seed.weight());
+    let mut counts = vec![0.0f64; num_canonical_kmers(seed.weight())];
+    let mut window = RollingKmer::new(2, seed.span);
+    for (pos, &b) in contig.iter().enumerate() {
+        window.push(base2number(opts.mask(b)));
+        if pos + 1 < seed.span {
+            continue;
+        }
+        if window.invalid & seed.care_mask == 0 {
+            counts[tbl[seed.extract(window.fwd) as usize] as usize] += 1.0;
+        }
+        if !seed.symmetric && window.invalid & seed.rev_care_mask == 0 {
+            counts[tbl[seed.extract(window.rev) as usize] as usize] += 1.0;
+        }
+    }
+    let mut out = vec![0.0; counts.len()];
+    normalize_into(&counts, &Normalization::L1, &mut out);
+    out
+}
+
+// the quotient 5,4,3,2 mer blocks of all contigs, each flattened like the other blocks of find_nMer_distributions
+fn quotient_blocks(quotients: &[NmerQuotients]) -> [Vec<f32>; 4] {
+    [quotients.par_iter().flat_map_iter(|q| q.0).collect(), quotients.par_iter().flat_map_iter(|q| q.1).collect(),
+     quotients.par_iter().flat_map_iter(|q| q.2).collect(), quotients.par_iter().flat_map_iter(|q| q.3).collect()]
+}
+
+
+// /// Given a contig of DNA bases, calculates L2 normed
+// /// "Distribution" over contigs 4mer count, where 4mers
+// /// are quotiented by reverse complement.
+fn contig_2_frac_nmer_counts(contig: &str, opts: CountOptions) -> FracNmerCounts {
+    let bases = contig.bytes().filter(|&b| b != b'\n').map(|b| opts.mask(b));
+    let mut counts = frac_nmer_counts(nmer_counts(bases.clone()));
+    if let Some(max_ambiguous) = opts.max_ambiguous {
+        add_ambiguous_nmers(&bases.collect::<Vec<u8>>(), max_ambiguous, &mut counts);
+    }
+    counts
+}
+
+fn contig_2_nmer_distrs(contig: &str, norms: &[Normalization], opts: CountOptions) -> NmerDistrs {
+    nmer_distrs_from_counts(contig_2_frac_nmer_counts(contig, opts), norms)
+}
+
+/// L1 normed canonical kmer distributions for each k in `ks` (in the order given), plus the number of
+/// windows of the largest k that contained a non ACGT base (and weren't spread out with max_ambiguous).
+/// ks must be in 1..=MAX_CANONICAL_K.
+fn contig_2_kmer_distrs_bytes(contig: &[u8], ks: &[usize], opts: CountOptions) -> (Vec<Vec<f32>>, usize) {
+    let max_k = ks.iter().copied().max().unwrap_or(1);
+    let tbls = ks.iter().map(|&k| canonical_table(k)).collect::<Vec<_>>();
+    let mut counts = ks.iter().map(|&k| vec![0.0f64; num_canonical_kmers(k)]).collect::<Vec<_>>();
+    let mut invalid_count = 0;
+
+    let mut window = RollingKmer::new(2, max_k);
+
+    for (pos, &b) in contig.iter().enumerate() {
+        window.push(base2number(opts.mask(b)));
+        if pos + 1 >= max_k && window.suffix(max_k).is_none() {
+            invalid_count += 1;
+        }
+        for (i, &k) in ks.iter().enumerate() {
+            if let Some(f) = window.suffix(k) {
+                counts[i][tbls[i][f as usize] as usize] += 1.0;
+            }
+        }
+    }
+
+    if let Some(max_ambiguous) = opts.max_ambiguous {
+        let masked = contig.iter().map(|&b| opts.mask(b)).collect::<Vec<u8>>();
+        let bases = AmbiguousBases::new(&masked, base2number);
+        let mut added = vec![0; ks.len()];
+        for (i, &k) in ks.iter().enumerate() {
+            added[i] = add_ambiguous_windows(&bases, 2, k, (contig.len() + 1).saturating_sub(k), max_ambiguous, tbls[i], &mut counts[i]);
+        }
+        // the windows of the largest k that were added aren't invalid any more
+        if let Some(i) = ks.iter().position(|&k| k == max_k) {
+            invalid_count -= added[i];
+        }
+    }
+
+    (counts.iter().map(|x| norm_slice(x)).collect(), invalid_count)
+}
+
+// (contig lens, one flat array per k, contig names)
+type KmerDistributions<'py> = (Vec<usize>, Vec<&'py PyArray1<f32>>, Vec<String>);
+
+fn check_canonical_ks(ks: &[usize]) -> PyResult<()> {
+    match ks.iter().find(|&&k| k == 0 || k > MAX_CANONICAL_K) {
+        Some(k) => Err(PyValueError::new_err(format!("canonical k must be between 1 and {}, got {}", MAX_CANONICAL_K, k))),
+        None => Ok(()),
+    }
+}
+
+// Up to this k, NoncanonicalCounts are a plain array of all 4^k kmers. Past it, most of the 4^k (16M for k = 12) are
+// never seen in a contig, so only the ones that are get stored.
+const MAX_DENSE_K: usize = 8;
+
+// Counts of every (not reverse compliment merged) kmer of one k, by kmer_to_16_bit_ray encoding, see noncanonical_counts.
+enum NoncanonicalCounts {
+    Dense(Vec<u32>),
+    // (code, count) of the kmers that were seen, sorted by code
+    Sparse(Vec<(u64, u32)>),
+}
+
+impl NoncanonicalCounts {
+    fn get(&self, code: u64) -> u32 {
+        match self {
+            NoncanonicalCounts::Dense(counts) => counts[code as usize],
+            NoncanonicalCounts::Sparse(counts) => counts.binary_search_by_key(&code, |&(c, _)| c).map_or(0, |i| counts[i].1),
+        }
+    }
+
+    // (code, count) of the kmers that were seen, by code
+    fn seen(&self) -> Box<dyn Iterator<Item = (u64, u32)> + '_> {
+        match self {
+            NoncanonicalCounts::Dense(counts) => Box::new(counts.iter().enumerate().filter(|&(_, &n)| n > 0).map(|(code, &n)| (code as u64, n))),
+            NoncanonicalCounts::Sparse(counts) => Box::new(counts.iter().copied()),
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.seen().map(|(_, n)| n as u64).sum()
+    }
+}
+
+// Counts of every (not reverse compliment merged) kmer for each k in ks, indexed by the kmer_to_16_bit_ray encoding.
+// With both_strands every window is counted once as read and once as its reverse compliment.
+// Memory is 4^k counts for k up to MAX_DENSE_K, and grows with the contig rather than 4^k past that.
+fn noncanonical_counts(contig: impl Iterator<Item = u8>, ks: &[usize], both_strands: bool) -> Vec<NoncanonicalCounts> {
+    let max_k = ks.iter().copied().max().unwrap_or(1);
+    let mut dense = ks.iter().map(|&k| if k <= MAX_DENSE_K { vec![0u32; 1 << (2 * k)] } else { Vec::new() }).collect::<Vec<_>>();
+    let mut seen = vec![Vec::new(); ks.len()];
+
+    let mut window = RollingKmer::new(2, max_k);
+
+    for b in contig {
+        window.push(base2number(b));
+        for (i, &k) in ks.iter().enumerate() {
+            if let Some(f) = window.suffix(k) {
+                let codes = std::iter::once(f).chain(both_strands.then(|| window.suffix_rev(k)));
+                if k <= MAX_DENSE_K {
+                    codes.for_each(|code| dense[i][code as usize] += 1);
+                } else {
+                    seen[i].extend(codes);
+                }
+            }
+        }
+    }
+    ks.iter()
+      .zip(dense.into_iter().zip(seen))
+      .map(|(&k, (dense, mut seen))| {
+          if k <= MAX_DENSE_K {
+              return NoncanonicalCounts::Dense(dense);
+          }
+          seen.par_sort_unstable();
+          let mut counts: Vec<(u64, u32)> = Vec::new();
+          for code in seen {
+              match counts.last_mut() {
+                  Some(last) if last.0 == code => last.1 += 1,
+                  _ => counts.push((code, 1)),
+              }
+          }
+          NoncanonicalCounts::Sparse(counts)
+      })
+      .collect()
+}
+
+/// Observed / expected ratio of every canonical k mer, where the expectation comes from a Markov model of the given
+/// order fit to the contig itself (both strands), e.g. k = 4, order = 0 is the "l4n1" feature:
+///
+///   P(w) = f(w[0..order+1]) * prod_{i=1}^{k-order-1} f(w[i..i+order+1]) / f(w[i..i+order])
+///
+/// with f the (order+1) and order mer frequencies (f of the empty word is 1). order must be < k <= MAX_CANONICAL_K.
+/// kmers that are never seen get 0, so there is nothing to divide by zero.
+fn markov_ratios(contig: impl Iterator<Item = u8>, k: usize, order: usize) -> Vec<f32> {
+    let ks = if order == 0 { vec![k, 1] } else { vec![k, order + 1, order] };
+    let counts = noncanonical_counts(contig, &ks, true);
+    let sums = counts.iter().map(|c| c.total()).collect::<Vec<_>>();
+    // only the kmers that were seen are looked at, so sums[i] > 0
+    let freq = |i: usize, code: u64| counts[i].get(code) as f64 / sums[i] as f64;
+
+    let tbl = canonical_table(k);
+    let mut result = vec![0.0f32; num_canonical_kmers(k)];
+
+    for (code, n) in counts[0].seen() {
+        let observed = n as f64 / sums[0] as f64;
+        let mut expected = 1.0;
+        for i in 0..k - order {
+            expected *= freq(1, (code >> (2 * (k - i - order - 1))) & low_bits(2 * (order + 1)));
+            if i > 0 && order > 0 {
+                expected /= freq(2, (code >> (2 * (k - i - order))) & low_bits(2 * order));
+            }
+        }
+        if expected > 0.0 {
+            result[tbl[code as usize] as usize] = (observed / expected) as f32;
+        }
+    }
+    result
+}
+
+fn check_markov(k: usize, order: usize) -> PyResult<()> {
+    check_canonical_ks(&[k])?;
+    if order >= k {
+        return Err(PyValueError::new_err(format!("markov order must be less than k, got k = {}, order = {}", k, order)));
+    }
+    Ok(())
+}
+
+// NCBI translation tables, amino acids of the codons in NCBI order (first, second, third base each in TCAG order).
+const GENETIC_CODES: [(usize, &str); 9] = [
+    (1, "FFLLSSSSYY**CC*WLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG"),  // standard
+    (2, "FFLLSSSSYY**CCWWLLLLPPPPHHQQRRRRIIMMTTTTNNKKSS**VVVVAAAADDEEGGGG"),  // vertebrate mitochondrial
+    (3, "FFLLSSSSYY**CCWWTTTTPPPPHHQQRRRRIIMMTTTTNNKKSSRRVVVVAAAADDEEGGGG"),  // yeast mitochondrial
+    (4, "FFLLSSSSYY**CCWWLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG"),  // mycoplasma / spiroplasma
+    (5, "FFLLSSSSYY**CCWWLLLLPPPPHHQQRRRRIIMMTTTTNNKKSSSSVVVVAAAADDEEGGGG"),  // invertebrate mitochondrial
+    (6, "FFLLSSSSYYQQCC*WLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG"),  // ciliate nuclear
+    (9, "FFLLSSSSYY**CCWWLLLLPPPPHHQQRRRRIIIMTTTTNNNKSSSSVVVVAAAADDEEGGGG"),  // echinoderm / flatworm mitochondrial
+    (11, "FFLLSSSSYY**CC*WLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG"), // bacterial, archaeal and plant plastid
+    (25, "FFLLSSSSYY**CCGWLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG"), // SR1 and gracilibacteria
+];
+
+// Order of the amino acid frequencies, stop last. Dipeptides are pairs of the first 20, first * 20 + second.
+const AMINO_ACIDS: &[u8; 21] = b"ACDEFGHIKLMNPQRSTVWY*";
+const STOP: u8 = 20;
+
+// Index into AMINO_ACIDS of every codon, by its kmer_to_16_bit_ray 3 mer code, from a GENETIC_CODES string.
+fn translation_table(aas: &str) -> [u8; 64] {
+    // A, T, C, G -> position in TCAG
+    const NCBI_ORDER: [usize; 4] = [2, 0, 1, 3];
+    let mut code = [0u8; 64];
+    for (codon, aa) in code.iter_mut().enumerate() {
+        let ncbi = 16 * NCBI_ORDER[codon >> 4] + 4 * NCBI_ORDER[(codon >> 2) & 3] + NCBI_ORDER[codon & 3];
+        *aa = AMINO_ACIDS.iter().position(|&x| x == aas.as_bytes()[ncbi]).unwrap() as u8;
+    }
+    code
+}
+
+fn genetic_code(table: usize) -> PyResult<[u8; 64]> {
+    GENETIC_CODES.iter()
+                 .find(|(id, _)| *id == table)
+                 .map(|(_, aas)| translation_table(aas))
+                 .ok_or_else(|| PyValueError::new_err(format!("unsupported genetic code {}, expected one of {:?}",
+                                                              table, GENETIC_CODES.iter().map(|x| x.0).collect::<Vec<_>>())))
+}
+
+// codon usage (by kmer_to_16_bit_ray 3 mer code), amino acid (AMINO_ACIDS order) and dipeptide frequencies
+type CodonDistrs = ([f32; 64], [f32; 21], [f32; 400]);
+
+// L1 normed, all 0 if nothing was counted (e.g. no ORF was long enough)
+fn freqs_or_zero<const N: usize>(counts: [u32; N]) -> [f32; N] {
+    if counts.iter().all(|&x| x == 0) { [0.0; N] } else { norm_vector(counts_to_f64(counts)) }
+}
+
+// Translates the contig in all six frames and counts codons, amino acids and dipeptides. Codons with a non ACGT base are
+// skipped and break dipeptides. With min_orf_len, only the stop to stop stretches (ORFs) of at least that many codons are
+// counted, without their stop codons; otherwise every codon is counted, stops included (dipeptides never include a stop).
+fn contig_2_codon_distrs_bytes(contig: &[u8], code: &[u8; 64], min_orf_len: Option<usize>) -> CodonDistrs {
+    let mut codon_counts = [0u32; 64];
+    let mut aa_counts = [0u32; 21];
+    let mut dipeptide_counts = [0u32; 400];
+
+    let fwd = contig.iter().map(|&b| base2number(b)).collect::<Vec<_>>();
+    let rev = fwd.iter().rev().map(|b| b.map(|b| b ^ 1)).collect::<Vec<_>>();
+
+    for strand in [&fwd, &rev] {
+        for frame in 0..3 {
+            let codons = strand[frame.min(strand.len())..]
+                    .chunks_exact(3)
+                    .map(|c| Some(((c[0]? as usize) << 4) | ((c[1]? as usize) << 2) | c[2]? as usize))
+                    .collect::<Vec<_>>();
+
+            // stretches of codons to count: split at invalid codons, and at stops for ORFs
+            let stretches = codons.split(|c| match c {
+                None => true,
+                Some(c) => min_orf_len.is_some() && code[*c] == STOP,
+            });
+            for stretch in stretches {
+                if stretch.len() < min_orf_len.unwrap_or(0) {
+                    continue;
+                }
+                let mut prev: Option<u8> = None;
+                for &c in stretch.iter().flatten() {
+                    let aa = code[c];
+                    codon_counts[c] += 1;
+                    aa_counts[aa as usize] += 1;
+                    if let (Some(p), true) = (prev, aa != STOP) {
+                        dipeptide_counts[p as usize * 20 + aa as usize] += 1;
+                    }
+                    prev = if aa != STOP { Some(aa) } else { None };
+                }
+            }
+        }
+    }
+
+    (freqs_or_zero(codon_counts), freqs_or_zero(aa_counts), freqs_or_zero(dipeptide_counts))
+}
+
+// (contig_lens, codon usage, amino acid, dipeptide matrices, contig_names)
+type CodonDistributions<'py> = (Vec<usize>, &'py PyArray2<f32>, &'py PyArray2<f32>, &'py PyArray2<f32>, Vec<String>);
+
+fn freqs_matrix<'py, const N: usize>(py: Python<'py>, rows: impl Iterator<Item = [f32; N]>) -> &'py PyArray2<f32> {
+    let flat = rows.flatten().collect::<Vec<f32>>();
+    let num_rows = flat.len() / N;
+    flat.into_pyarray(py).reshape([num_rows, N]).unwrap()
+}
+
+// contig_lens, ks, one (num_contigs, block size) matrix per k, contig_names
+type BinaryDistributions<'py> = (Vec<usize>, Vec<usize>, Vec<&'py PyArray2<f32>>, Vec<String>);
+// ks, one block per k, number of invalid windows
+type SingleBinaryDistributions<'py> = (Vec<usize>, Vec<&'py PyArray1<f32>>, usize);
+
+fn check_orf_mode(mode: &str, min_orf_len: usize) -> PyResult<Option<usize>> {
+    match mode {
+        "six_frame" => Ok(None),
+        "orf" => Ok(Some(min_orf_len)),
+        _ => Err(PyValueError::new_err(format!("unknown mode {:?}, expected \"six_frame\" or \"orf\"", mode))),
+    }
+}
+
+// Columns of a window profile: the 10 feature blocks of find_nMer_distributions side by side, in the same order.
+const WINDOW_PROFILE_SIZE: usize = 512 + 136 + 32 + 10 + 2 + 528 + 256 + 136 + 64 + 36;
+
+// (start, end) of every window of length `window` every `step` bases that fits in a contig of length len.
+fn window_starts(len: usize, window: usize, step: usize) -> Vec<(usize, usize)> {
+    (0..(len + 1).saturating_sub(window)).step_by(step).map(|start| (start, start + window)).collect()
+}
+
+fn check_windows(window: usize, step: usize) -> PyResult<()> {
+    if window == 0 || step == 0 {
+        return Err(PyValueError::new_err(format!("window and step must be positive, got window = {}, step = {}", window, step)));
+    }
+    Ok(())
+}
+
+// One window profile row (see WINDOW_PROFILE_SIZE).
+fn window_profile(ctg: &[u8], norms: &[Normalization], opts: CountOptions) -> Vec<f32> {
+    let nmers = contig_2_nmer_distrs_bytes(ctg, &norms[..5], opts);
+    let rymers = find_rymers_bytes(ctg, &norms[5..], opts);
+    let mut row = Vec::with_capacity(WINDOW_PROFILE_SIZE);
+    row.extend_from_slice(&nmers.0);
+    row.extend_from_slice(&nmers.1);
+    row.extend_from_slice(&nmers.3);
+    row.extend_from_slice(&nmers.4);
+    row.extend_from_slice(&nmers.5);
+    row.extend_from_slice(&rymers.0);
+    row.extend_from_slice(&rymers.1);
+    row.extend_from_slice(&rymers.2);
+    row.extend_from_slice(&rymers.3);
+    row.extend_from_slice(&rymers.4);
+    row
+}
+
+// Window profiles of every contig: for each contig the flat (num_windows, WINDOW_PROFILE_SIZE) matrix and the window
+// coordinates. Windows of all contigs go to rayon together, so a few long contigs don't leave threads idle.
+type WindowProfiles = Vec<(Vec<f32>, Vec<(usize, usize)>)>;
+
+fn window_profiles(contigs: &[&[u8]], window: usize, step: usize, norms: &[Normalization], opts: CountOptions) -> WindowProfiles {
+    let windows = contigs.iter()
+                         .map(|ctg| window_starts(ctg.len(), window, step))
+                         .collect::<Vec<_>>();
+
+    let mut rows = windows.iter()
+                          .enumerate()
+                          .flat_map(|(i, w)| w.iter().map(move |&(start, end)| (i, start, end)))
+                          .collect::<Vec<_>>()
+                          .par_iter()
+                          .map(|&(i, start, end)| window_profile(&contigs[i][start..end], norms, opts))
+                          .collect::<Vec<_>>()
+                          .into_iter();
+
+    windows.into_iter()
+           .map(|w| (rows.by_ref().take(w.len()).flatten().collect::<Vec<f32>>(), w))
+           .collect()
+}
+
+// (profile matrix, window coordinates) of each contig
+type WindowMatrices<'py> = (Vec<&'py PyArray2<f32>>, Vec<&'py PyArray2<usize>>);
+// (contig_lens, profile matrices, window coordinates, contig_names)
+type WindowDistributions<'py> = (Vec<usize>, Vec<&'py PyArray2<f32>>, Vec<&'py PyArray2<usize>>, Vec<String>);
+
+fn window_matrices<'py>(py: Python<'py>, profiles: WindowProfiles) -> WindowMatrices<'py> {
+    profiles.into_iter()
+            .map(|(rows, w)| {
+                let num_windows = w.len();
+                let coords = w.into_iter().flat_map(|(start, end)| [start, end]).collect::<Vec<_>>();
+                (rows.into_pyarray(py).reshape([num_windows, WINDOW_PROFILE_SIZE]).unwrap(),
+                 coords.into_pyarray(py).reshape([num_windows, 2]).unwrap())
+            })
+            .unzip()
+}
+
+// Columns of find_contig_stats, in order:
+// gc, gc_skew (G-C)/(G+C), at_skew (A-T)/(A+T), n_fraction, ambiguous_fraction (anything but ACGT), longest_homopolymer,
+// homopolymer_runs (runs of at least homopolymer_len), homopolymer_fraction (bases in those runs),
+// invalid 5 base windows, invalid RY 10 base windows (the same invalid counts as find_nMer_counts).
+const NUM_CONTIG_STATS: usize = 10;
+
+fn skew(a: usize, b: usize) -> f32 {
+    if a + b > 0 { (a as f32 - b as f32) / (a + b) as f32 } else { 0.0 }
+}
+
+// One pass over the contig for every column of NUM_CONTIG_STATS.
+fn contig_stats(contig: &[u8], homopolymer_len: usize) -> [f32; NUM_CONTIG_STATS] {
+    let mut base_counts = [0usize; 4];
+    let (mut n_count, mut ambiguous_count) = (0, 0);
+    let (mut longest_run, mut num_runs, mut run_bases) = (0, 0, 0);
+    let (mut invalid_count, mut invalid_ry_count) = (0, 0);
+
+    let mut run = (None, 0);
+    // bases since the last one that base2number (base2number_ry) didn't know
+    let (mut since_invalid, mut since_invalid_ry) = (0, 0);
+
+    let mut end_run = |len: usize| {
+        longest_run = longest_run.max(len);
+        if len >= homopolymer_len {
+            num_runs += 1;
+            run_bases += len;
+        }
+    };
+
+    for (pos, &b) in contig.iter().enumerate() {
+        let code = base2number(b);
+        match code {
+            Some(c) => base_counts[c as usize] += 1,
+            None => {
+                ambiguous_count += 1;
+                n_count += b.eq_ignore_ascii_case(&b'n') as usize;
+            },
+        }
+
+        if code.is_some() && code == run.0 {
+            run.1 += 1;
+        } else {
+            end_run(run.1);
+            run = (code, code.is_some() as usize);
+        }
+
+        since_invalid = if code.is_some() { since_invalid + 1 } else { 0 };
+        since_invalid_ry = if base2number_ry(b).is_some() { since_invalid_ry + 1 } else { 0 };
+        if pos >= 4 && since_invalid < 5 {
+            invalid_count += 1;
+        }
+        if pos >= 9 && since_invalid_ry < 10 {
+            invalid_ry_count += 1;
+        }
+    }
+    end_run(run.1);
+
+    let [a, t, c, g] = base_counts;
+    let len = contig.len().max(1) as f32;
+    [
+        if a + t + c + g > 0 { (g + c) as f32 / (a + t + c + g) as f32 } else { 0.0 },
+        skew(g, c),
+        skew(a, t),
+        n_count as f32 / len,
+        ambiguous_count as f32 / len,
+        longest_run as f32,
+        num_runs as f32,
+        run_bases as f32 / len,
+        invalid_count as f32,
+        invalid_ry_count as f32,
+    ]
+}
+
+// GC and AT skew of consecutive `window` base windows, with their running sums (the usual cumulative skew curve, whose
+// minimum and maximum point at the origin and terminus of replication): columns gc_skew, cumulative gc_skew, at_skew, cumulative at_skew.
+fn skew_curve(contig: &[u8], window: usize) -> Vec<[f32; 4]> {
+    let (mut cumulative_gc, mut cumulative_at) = (0.0, 0.0);
+    window_starts(contig.len(), window, window)
+        .into_iter()
+        .map(|(start, end)| {
+            let mut base_counts = [0usize; 4];
+            for c in contig[start..end].iter().filter_map(|&b| base2number(b)) {
+                base_counts[c as usize] += 1;
+            }
+            let [a, t, c, g] = base_counts;
+            cumulative_gc += skew(g, c);
+            cumulative_at += skew(a, t);
+            [skew(g, c), cumulative_gc, skew(a, t), cumulative_at]
+        })
+        .collect()
+}
+
+// (contig_lens, stats, skew curves if asked for, contig_names)
+type ContigStats<'py> = (Vec<usize>, &'py PyArray2<f32>, Option<Vec<&'py PyArray2<f32>>>, Vec<String>);
+
+// Raw (un-normalized) counts, one row per contig:
+// 5,4,3,2,1 mers, RY 10,9,8,7,6 mers, then the invalid window counts (column 0: 5 base windows, column 1: RY 10 base windows).
+// pyo3 only converts tuples up to 12 long to python, which is why this goes back to python as its own tuple.
+type CountMatrices<'py> = (&'py PyArray2<u32>, &'py PyArray2<u32>, &'py PyArray2<u32>, &'py PyArray2<u32>, &'py PyArray2<u32>,
+                           &'py PyArray2<u32>, &'py PyArray2<u32>, &'py PyArray2<u32>, &'py PyArray2<u32>, &'py PyArray2<u32>,
+                           &'py PyArray2<u32>);
+
+// 4,5,l4n1 (or markov),3,2,1 mers, RY 10,9,8,7,6 mers and the number of invalid RY windows of one contig, see find_single
+type SingleDistributions<'py> = (&'py PyArray1<f32>, &'py PyArray1<f32>, &'py PyArray1<f32>, &'py PyArray1<f32>, &'py PyArray1<f32>, &'py PyArray1<f32>,
+                                 &'py PyArray1<f32>, &'py PyArray1<f32>, &'py PyArray1<f32>, &'py PyArray1<f32>, &'py PyArray1<f32>, usize);
+
+// l4n1,5,4,3,2,1 mers and the invalid window counts of the sampled contigs, see sampling
+type SampledDistributions<'py> = (&'py PyArray1<f32>, &'py PyArray1<f32>, &'py PyArray1<f32>, &'py PyArray1<f32>, &'py PyArray1<f32>, &'py PyArray1<f32>,
+                                  &'py PyArray1<usize>);
+
+// (contig_lens, unmasked counts, masked counts, masked fraction, contig_names), see find_masked_counts
+type MaskedCounts<'py> = (Vec<usize>, CountMatrices<'py>, CountMatrices<'py>, &'py PyArray1<f32>, Vec<String>);
+
+// Counts of the 10 feature blocks in output order (canonical, or every kmer when stranded), then the number of invalid
+// 5 base windows and invalid RY 10 base windows.
+type BlockCounts = (Vec<Vec<u32>>, usize, usize);
+
+// Width of each block when nothing is merged with its reverse compliment: 4^k for 5,4,3,2,1 mers, 2^k for RY 10,9,8,7,6 mers.
+const STRANDED_BLOCK_SIZES: [usize; NUM_FEATURE_BLOCKS] = [1024, 256, 64, 16, 4, 1024, 512, 256, 128, 64];
+
+// Counts every (not reverse compliment merged) 5,4,3,2,1 mer and RY 10,9,8,7,6 mer, indexed by the kmer_to_16_bit_ray
+// encoding (1 bit per base2number_ry base for RY). Windows are the same as nmer_counts and rymer_counts, so the totals match.
+fn stranded_counts(contig: impl Iterator<Item = u8> + Clone) -> BlockCounts {
+    let mut counts = STRANDED_BLOCK_SIZES.iter().map(|&n| vec![0u32; n]).collect::<Vec<_>>();
+    let (mut invalid_count, mut invalid_ry_count) = (0, 0);
+
+    let mut window = RollingKmer::new(2, 5);
+    for (pos, b) in contig.clone().enumerate() {
+        window.push(base2number(b));
+        if pos >= 4 && window.suffix(5).is_none() {
+            invalid_count += 1;
+        }
+        for (i, k) in (1..=5).rev().enumerate() {
+            if let Some(f) = window.suffix(k) {
+                counts[i][f as usize] += 1;
+            }
+        }
+    }
+
+    let mut window = RollingKmer::new(1, 10);
+    for (pos, b) in contig.enumerate() {
+        window.push(base2number_ry(b));
+        if pos < 9 {
+            continue;
+        }
+        if window.prefix(10).is_none() {
+            invalid_ry_count += 1;
+        }
+        for (i, k) in (6..=10).rev().enumerate() {
+            if let Some(f) = window.prefix(k) {
+                counts[5 + i][f as usize] += 1;
+            }
+        }
+    }
+
+    (counts, invalid_count, invalid_ry_count)
+}
+
+fn block_counts(contig: impl Iterator<Item = u8> + Clone, stranded: bool) -> BlockCounts {
+    if stranded {
+        return stranded_counts(contig);
+    }
+    let (n, r) = (nmer_counts(contig.clone()), rymer_counts(contig));
+    (vec![n.0.to_vec(), n.1.to_vec(), n.2.to_vec(), n.3.to_vec(), n.4.to_vec(),
+          r.0.to_vec(), r.1.to_vec(), r.2.to_vec(), r.3.to_vec(), r.4.to_vec()], n.5, r.5)
+}
+
+// The fraction of the contig's bases that are soft-masked (lowercase), the masked fraction of find_masked_counts.
+fn lowercase_fraction(contig: &[u8]) -> f32 {
+    contig.iter().filter(|b| b.is_ascii_lowercase()).count() as f32 / contig.len().max(1) as f32
+}
+
+// Like block_counts, but windows with a soft-masked (lowercase) base are counted into a second profile:
+// (unmasked counts, masked counts, number of lowercase bases). Invalid windows go to the profile they would have been in.
+fn masked_block_counts(contig: impl Iterator<Item = u8> + Clone, stranded: bool) -> (BlockCounts, BlockCounts, usize) {
+    let sizes = if stranded { STRANDED_BLOCK_SIZES } else { FEATURE_BLOCK_SIZES };
+    let tables: [&[u32]; NUM_FEATURE_BLOCKS] = [&FIVEMERTABLE, &FOURMERTABLE, &THREEMERTABLE, &TWOMERTABLE, &ONEMERTABLE,
+                                                &RY10TBL, &RY9TBL, &RY8TBL, &RY7TBL, &RY6TBL];
+    let index = |block: usize, f: u64| if stranded { f as usize } else { tables[block][f as usize] as usize };
+
+    let mut profiles = [0, 1].map(|_| (sizes.iter().map(|&n| vec![0u32; n]).collect::<Vec<_>>(), 0, 0));
+    let mut num_masked = 0;
+
+    // bit i is set if the base i positions back was lowercase
+    let mut lower = 0u64;
+    let mut window = RollingKmer::new(2, 5);
+    for (pos, b) in contig.clone().enumerate() {
+        window.push(base2number(b));
+        lower = (lower << 1) | b.is_ascii_lowercase() as u64;
+        num_masked += b.is_ascii_lowercase() as usize;
+        if pos >= 4 && window.suffix(5).is_none() {
+            profiles[(lower & low_bits(5) != 0) as usize].1 += 1;
+        }
+        for (i, k) in (1..=5).rev().enumerate() {
+            if let Some(f) = window.suffix(k) {
+                profiles[(lower & low_bits(k) != 0) as usize].0[i][index(i, f)] += 1;
+            }
+        }
+    }
+
+    let mut lower = 0u64;
+    let mut window = RollingKmer::new(1, 10);
+    for (pos, b) in contig.enumerate() {
+        window.push(base2number_ry(b));
+        lower = (lower << 1) | b.is_ascii_lowercase() as u64;
+        if pos < 9 {
+            continue;
+        }
+        if window.prefix(10).is_none() {
+            profiles[(lower & low_bits(10) != 0) as usize].2 += 1;
+        }
+        for (i, k) in (6..=10).rev().enumerate() {
+            if let Some(f) = window.prefix(k) {
+                profiles[((lower >> (10 - k)) & low_bits(k) != 0) as usize].0[5 + i][index(5 + i, f)] += 1;
+            }
+        }
+    }
+
+    let [unmasked, masked] = profiles;
+    (unmasked, masked, num_masked)
+}
+
+fn counts_matrix<'py, 'a>(py: Python<'py>, width: usize, rows: impl Iterator<Item = &'a [u32]>) -> &'py PyArray2<u32> {
+    let flat = rows.flatten().copied().collect::<Vec<u32>>();
+    let num_rows = flat.len() / width;
+    flat.into_pyarray(py).reshape([num_rows, width]).unwrap()
+}
+
+fn count_matrices<'py>(py: Python<'py>, counts: &[BlockCounts], stranded: bool) -> CountMatrices<'py> {
+    let sizes = if stranded { STRANDED_BLOCK_SIZES } else { FEATURE_BLOCK_SIZES };
+    let m = (0..NUM_FEATURE_BLOCKS)
+        .map(|i| counts_matrix(py, sizes[i], counts.iter().map(|x| &x.0[i][..])))
+        .collect::<Vec<_>>();
+    let invalid = counts.iter().map(|x| [x.1 as u32, x.2 as u32]).collect::<Vec<_>>();
+    (m[0], m[1], m[2], m[3], m[4], m[5], m[6], m[7], m[8], m[9],
+     counts_matrix(py, 2, invalid.iter().map(|x| &x[..])))
+}
+
+use numpy::IntoPyArray;
+use pyo3::exceptions::{PyIOError, PyValueError};
+use std::io::Write;
+use pyo3::Python;
 use pyo3::types::PyString;
-use flate2::read::GzDecoder;
+use flate2::read::{GzDecoder, MultiGzDecoder};
 use std::io::Read;
 //#use std::str::pattern::Pattern;
 use numpy::array::PyArray1;
 use hashbrown::hash_map::HashMap;
+use std::collections::BTreeMap;
 use std::path::Path;
 
 use numpy::array::PyArray;
-use rayon::iter::ParallelDrainRange;
 use pyo3::types::PySequence;
+use pyo3::types::PyTuple;
 
 
 
@@ -1211,7 +3149,83 @@ fn find_markers(filepath: &str, names: &[String]) -> Vec<[u32; 40]> {
         .collect()
 }
 
+// A genome in a FastaDataBase packed to 2 bits per base (A=0, T=1, C=2, G=3, first base in the highest bits of each byte).
+// Everything that isn't ACGT is kept as runs of (start, end, uppercased byte), and lowercase (soft-masked) stretches as
+// (start, end) runs, so decoding gives back exactly what was read. Both lists are sorted and usually tiny next to the sequence.
+struct PackedSeq {
+    bases: Vec<u8>,
+    len: usize,
+    ambiguous: Vec<(usize, usize, u8)>,
+    lowercase: Vec<(usize, usize)>,
+}
+
+impl PackedSeq {
+    fn new(seq: &[u8]) -> Self {
+        let mut bases = vec![0u8; seq.len().div_ceil(4)];
+        let mut ambiguous: Vec<(usize, usize, u8)> = Vec::new();
+        let mut lowercase: Vec<(usize, usize)> = Vec::new();
+        for (pos, &b) in seq.iter().enumerate() {
+            let upper = b.to_ascii_uppercase();
+            match base2number(upper) {
+                Some(code) => bases[pos / 4] |= code << (6 - 2 * (pos % 4)),
+                None => match ambiguous.last_mut() {
+                    Some(run) if run.1 == pos && run.2 == upper => run.1 += 1,
+                    _ => ambiguous.push((pos, pos + 1, upper)),
+                },
+            }
+            if b.is_ascii_lowercase() {
+                match lowercase.last_mut() {
+                    Some(run) if run.1 == pos => run.1 += 1,
+                    _ => lowercase.push((pos, pos + 1)),
+                }
+            }
+        }
+        PackedSeq { bases, len: seq.len(), ambiguous, lowercase }
+    }
+
+    fn decode(&self, start: usize, len: usize) -> Vec<u8> {
+        let end = start + len;
+        assert!(end <= self.len);
+        let mut out = (start..end).map(|pos| NUCLEOTIDES[((self.bases[pos / 4] >> (6 - 2 * (pos % 4))) & 3) as usize])
+                                  .collect::<Vec<u8>>();
+
+        let first = self.ambiguous.partition_point(|&(_, e, _)| e <= start);
+        for &(s, e, b) in self.ambiguous[first..].iter().take_while(|&&(s, _, _)| s < end) {
+            out[s.max(start) - start..e.min(end) - start].fill(b);
+        }
+        let first = self.lowercase.partition_point(|&(_, e)| e <= start);
+        for &(s, e) in self.lowercase[first..].iter().take_while(|&&(s, _)| s < end) {
+            out[s.max(start) - start..e.min(end) - start].make_ascii_lowercase();
+        }
+        out
+    }
+
+    fn stored_bytes(&self) -> usize {
+        self.bases.len() + self.ambiguous.len() * std::mem::size_of::<(usize, usize, u8)>() + self.lowercase.len() * std::mem::size_of::<(usize, usize)>()
+    }
+}
+
+// The sequences of one genome in a FastaDataBase: as read, or packed (FastaDataBase(..., packed=True)).
+enum SeqStorage {
+    Plain(Vec<u8>),
+    Packed(PackedSeq),
+}
+
+impl SeqStorage {
+    fn slice(&self, start: usize, len: usize) -> std::borrow::Cow<'_, [u8]> {
+        match self {
+            SeqStorage::Plain(seq) => std::borrow::Cow::Borrowed(&seq[start..start + len]),
+            SeqStorage::Packed(seq) => std::borrow::Cow::Owned(seq.decode(start, len)),
+        }
+    }
 
+    fn stored_bytes(&self) -> usize {
+        match self {
+            SeqStorage::Plain(seq) => seq.len(),
+            SeqStorage::Packed(seq) => seq.stored_bytes(),
+        }
+    }
+}
 
 #[pymodule]
 fn kmer_counter(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
@@ -1219,7 +3233,7 @@ fn kmer_counter(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
     #[pyclass]
     #[pyo3(name = "FastaDataBase")]
     struct FastaDataBase {
-        file_contents: Vec<Vec<u8>>,
+        file_contents: Vec<SeqStorage>,
         // list of all contigs; first tuple element describes start position in some file_contents[i] for unknown  i;
         // third gives len
         contigs: Vec<(usize, usize)>,
@@ -1242,19 +3256,53 @@ fn kmer_counter(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
     m.add_class::<FastaDataBase>()?;
 
     m.add_class::<PyFastaIterator>()?;
+
+    m.add_class::<FeatureNormalization>()?;
+
+    m.add_class::<Sketch>()?;
+    m.add_class::<KmerSpectrum>()?;
     
 
+// How often a sample is redrawn before giving up, see FastaDataBase::draw_sample
+const MAX_SAMPLE_DRAWS: usize = 1000;
+
+// The error sample and sample_counts raise when draw_sample gives up.
+fn sample_draws_error(contig_sample_size: usize) -> PyErr {
+    PyValueError::new_err(format!("no sample of {} bases without invalid windows found in {} draws, lower contig_sample_size \
+                                   (or min_quality, which turns low quality bases of the reads into Ns)", contig_sample_size, MAX_SAMPLE_DRAWS))
+}
+
 impl FastaDataBase  {
-    fn get_contig_slice(&self, file_idx: usize, contig_idx :usize , pos: usize, len: usize) -> &[u8] {
-        &(&self.file_contents[file_idx][self.contigs[self.contigs_start[file_idx] + contig_idx].0..self.contigs[self.contigs_start[file_idx] + contig_idx].0+self.contigs[self.contigs_start[file_idx] + contig_idx].1])[pos..pos+len]
+    // borrowed from the sequence as read, or decoded if the database is packed
+    fn get_contig_slice(&self, file_idx: usize, contig_idx :usize , pos: usize, len: usize) -> std::borrow::Cow<'_, [u8]> {
+        let (start, size) = self.contigs[self.contigs_start[file_idx] + contig_idx];
+        assert!(pos + len <= size);
+        self.file_contents[file_idx].slice(start + pos, len)
+    }
+
+    // features of a contig_sample_size long piece of genome file_idx (from a contig picked by length, at a uniform
+    // position), redrawn from the same genome while rejected says so. The draw loop of sample and sample_counts.
+    // None after MAX_SAMPLE_DRAWS rejected draws: a genome with too few clean stretches (e.g. reads masked with
+    // min_quality, where nearly every long piece has an N) would otherwise be redrawn forever.
+    fn draw_sample<T>(&self, file_idx: usize, contig_sample_size: usize, features: impl Fn(&[u8]) -> T, rejected: impl Fn(&T) -> bool) -> Option<T> {
+        let mut rng = rand::thread_rng();
+        (0..MAX_SAMPLE_DRAWS).find_map(|_| {
+            let contig_idx = self.weight_index[file_idx].sample(&mut rng);
+            let start_pos = rng.gen_range(0..self.get_contig_size_unch(file_idx, contig_idx) - contig_sample_size);
+            Some(features(&self.get_contig_slice(file_idx, contig_idx, start_pos, contig_sample_size))).filter(|x| !rejected(x))
+        })
     }
 }
 
 
 #[pymethods]
 impl FastaDataBase {
+    /// packed = True keeps the genomes at 2 bits per base (plus the positions of anything that isn't ACGT and of lowercase
+    /// stretches), about 4x less memory, and decodes the samples on the fly.
+    /// Fastq files (starting with '@') add every read as a contig; min_quality = q masks bases with a Phred quality below q.
     #[new]
-    fn new(contig_file_paths: Vec<String>, min_len: usize, myrank: usize,totalranks: usize) -> Self {
+    #[args(packed = "false", min_quality = "None")]
+    fn new(py: Python, contig_file_paths: Vec<String>, min_len: usize, myrank: usize,totalranks: usize, packed: bool, min_quality: Option<u8>) -> PyResult<Self> {
 
         let mut result = Self {
             file_contents: Vec::new(),
@@ -1272,26 +3320,8 @@ impl FastaDataBase {
                 bar.inc(1);
                 continue;
             }
-            let file_info = fs::metadata(contig_file).unwrap();
-            let file_size = file_info.len();
-
-            let mut file = File::open(contig_file).unwrap();
-
-            //read using multiple file handles for faster spead
-            let mut read_buffer: Vec<u8> = Vec::new();
-            par_read(&mut file, &mut read_buffer, file_size);
-
-            // if needs to be decompressed,  replace dataa buffer with decompressed one, otherwise leave as is;
-            // 'z' -> check for gz
-
-            let read_buffer = if contig_file.len() >3 && b'z'==contig_file.as_bytes()[contig_file.len()-1] {
-                let mut decom_buffer: Vec<u8> = Vec::new();
-                let mut gz = GzDecoder::new(&read_buffer[..]);
-                gz.read_to_end(&mut  decom_buffer).unwrap();
-                decom_buffer
-            } else {
-                read_buffer
-            };
+            // decompressed if needed, see Compression
+            let read_buffer = load_contig_file(py, contig_file)?;
 
 
             result.contigs_start.push(result.contigs.len());
@@ -1303,35 +3333,27 @@ impl FastaDataBase {
             // a vector of contig lengths, this is how we will weight draws
             let mut to_weights = Vec::new();
 
-            read_buffer.split_inclusive(|x| *x == b'>')
-                                   .for_each(|name_contig| {
-                                    
-
-                                        if let Some(end_of_name_pos) = name_contig.iter().position(|&r| r == b'\n') {
-                                            // leaves '\n' and '>', but we handle those when counting kmers
-
-                                                               // leaves '\n' and '>', but we handle those when counting kmers
-
-                                            //check if meets min size
-                                            let size = name_contig[end_of_name_pos+1..].iter().filter(|&&x| x != b'\n' && x != b'>').count();
-
-
-                                            if size >= min_len {
-                                                //dbg!("included");
-                                                contig_buffer.extend(name_contig[end_of_name_pos+1..].iter().filter(|&&x| x != b'\n' && x != b'>'));
-                                                result.contigs.push((current_pos_in_buffer, size));
-                                                current_pos_in_buffer += size;
-                                                to_weights.push(size);
-                                            } 
-                                            //dbg!("hi", size);
-                                            // result.contigs.push((current_pos_in_buffer+end_of_name_pos+1, name_contig[end_of_name_pos+1..].len() ));
-                                            
-                                        }
-                                        
-                                   });
+            if is_fastq(&read_buffer) {
+                for (_, read) in parse_fastq(&read_buffer, min_len, min_quality).map_err(|e| contig_file_error(contig_file, e))? {
+                    contig_buffer.extend_from_slice(&read);
+                    result.contigs.push((current_pos_in_buffer, read.len()));
+                    current_pos_in_buffer += read.len();
+                    to_weights.push(read.len());
+                }
+            } else {
+            for record in fasta_records(&read_buffer) {
+                let size = record.bases().count();
+                if size >= min_len {
+                    contig_buffer.extend(record.bases());
+                    result.contigs.push((current_pos_in_buffer, size));
+                    current_pos_in_buffer += size;
+                    to_weights.push(size);
+                }
+            }
+            }
             if let Ok(a) = WeightedIndex::new(&to_weights) {
                 result.weight_index.push(a);
-                result.file_contents.push(contig_buffer);
+                result.file_contents.push(if packed { SeqStorage::Packed(PackedSeq::new(&contig_buffer)) } else { SeqStorage::Plain(contig_buffer) });
             } else {
                 result.contigs_start.pop();
             }
@@ -1340,7 +3362,7 @@ impl FastaDataBase {
         }
         bar.finish();
 
-        return result;
+        Ok(result)
     }
     fn get_num_contig(&self, file_idx: usize) -> isize {
         if file_idx >= self.contigs_start.len() {
@@ -1390,12 +3412,17 @@ impl FastaDataBase {
         };
 
         if contig_idx >= num_contigs {
-            return  String::new();
+            String::new()
         } else {
-            return std::str::from_utf8(&self.file_contents[file_idx][self.contigs[self.contigs_start[file_idx] + contig_idx].0..self.contigs[self.contigs_start[file_idx] + contig_idx].0+self.contigs[self.contigs_start[file_idx] + contig_idx].1]).unwrap().to_string();
+            std::str::from_utf8(&self.get_contig_slice(file_idx, contig_idx, 0, self.get_contig_size_unch(file_idx, contig_idx))).unwrap().to_string()
         }
     }
 
+    /// Bytes used to hold the loaded sequences.
+    fn stored_bytes(&self) -> usize {
+        self.file_contents.iter().map(|x| x.stored_bytes()).sum()
+    }
+
     fn sample_beta<'py>(&'py self, py: Python<'py>, n: usize, contig_sample_size: usize) -> (&'py PyArray1<u32>, &'py PyArray1<usize> ) {
         let qq =  py.allow_threads(move || {
             let mut rng = rand::thread_rng();
@@ -1410,7 +3437,7 @@ impl FastaDataBase {
 
             let pre_tens = to_sample.par_iter()
             .map(| (file_idx,contig_idx,start_pos)|  (self.get_contig_slice(*file_idx,*contig_idx,*start_pos, contig_sample_size), *file_idx))
-            .map_init(|| StdRng::from_entropy(), |rng, (ctg, file_idx)| (contig_to_labeled_flip(ctg,rng.gen_bool(0.5)), self.totalranks*file_idx + self.myrank) )
+            .map_init(StdRng::from_entropy, |rng, (ctg, file_idx)| (contig_to_labeled_flip(&ctg,rng.gen_bool(0.5)), self.totalranks*file_idx + self.myrank) )
             .map( |mut x| { 
                 while   x.0.iter().filter(|&x| *x != 1024).count() < contig_sample_size {
                     let mut rng = rand::thread_rng();
@@ -1418,7 +3445,7 @@ impl FastaDataBase {
                     let contig_idx = self.weight_index[file_idx].sample(&mut rng);// rng.gen_range(0..self.get_num_contig_unch(file_idx));
                     let start_pos =  rng.gen_range(0..self.get_contig_size_unch(file_idx, contig_idx)- contig_sample_size);
                     let ctg = self.get_contig_slice(file_idx,contig_idx,start_pos, contig_sample_size);
-                    x = (contig_to_labeled(ctg), self.totalranks*file_idx + self.myrank) ;
+                    x = (contig_to_labeled(&ctg), self.totalranks*file_idx + self.myrank) ;
                 }
                 x
             })
@@ -1455,7 +3482,7 @@ impl FastaDataBase {
 
             let pre_tens = to_sample.par_iter()
             .map(| (file_idx,contig_idx,start_pos)|  (self.get_contig_slice(*file_idx,*contig_idx,*start_pos, contig_sample_size), *file_idx))
-            .map(|(ctg, file_idx)| (contig_to_labeled2(ctg), file_idx) )
+            .map(|(ctg, file_idx)| (contig_to_labeled2(&ctg), file_idx) )
             .map( |mut x| { 
                 while   x.0.iter().filter(|&x| *x != 4).count() < 2000 {
                     let mut rng = rand::thread_rng();
@@ -1463,7 +3490,7 @@ impl FastaDataBase {
                     let contig_idx = self.weight_index[file_idx].sample(&mut rng);// rng.gen_range(0..self.get_num_contig_unch(file_idx));
                     let start_pos =  rng.gen_range(0..self.get_contig_size_unch(file_idx, contig_idx)- contig_sample_size);
                     let ctg = self.get_contig_slice(file_idx,contig_idx,start_pos, contig_sample_size);
-                    x = (contig_to_labeled2(ctg), file_idx) ;
+                    x = (contig_to_labeled2(&ctg), file_idx) ;
                 }
                 x
             })
@@ -1486,35 +3513,44 @@ impl FastaDataBase {
     }
 
 
-    fn sample<'py>(&'py self, py: Python<'py>, n: usize, contig_sample_size: usize) -> (&'py PyArray1<f32>,&'py PyArray1<f32>,&'py PyArray1<f32>,&'py PyArray1<f32>,&'py PyArray1<f32>,&'py PyArray1<f32>, &'py PyArray1<f32>,&'py PyArray1<f32>,&'py PyArray1<f32>,&'py PyArray1<f32>,&'py PyArray1<usize> ) {
+    /// markov = (k, order) fills the second ("l4n1") output with markov_ratios instead of 4 mers.
+    /// max_ambiguous = m gives windows with up to m ambiguous IUPAC bases fractional counts (see add_ambiguous_windows).
+    /// masking = "skip" drops windows with soft-masked (lowercase) bases.
+    /// quotients = True adds the quotiented 5,4,3,2 mer blocks (see nmer_quotients) after the RY 6 mers, before the labels.
+    /// seeds = ["11011", ...] adds one spaced seed block per mask (see seed_distr) after those, still before the labels.
+    /// masked_fraction = True adds the fraction of each sample's bases that are lowercase after those, before the labels.
+    /// Samples with an invalid window (e.g. an N) are redrawn, up to MAX_SAMPLE_DRAWS times before raising a ValueError. Bases
+    /// masked with min_quality are Ns too, so with long low quality reads keep contig_sample_size well below the read length.
+    #[args(norm = "None", markov = "None", max_ambiguous = "None", masking = "\"all\"", quotients = "false", seeds = "None", masked_fraction = "false")]
+    #[allow(clippy::too_many_arguments)]
+    fn sample(&self, py: Python, n: usize, contig_sample_size: usize, norm: Option<PyRef<FeatureNormalization>>, markov: Option<(usize, usize)>, max_ambiguous: Option<usize>, masking: &str, quotients: bool, seeds: Option<Vec<String>>, masked_fraction: bool) -> PyResult<PyObject> {
+        if let Some((k, order)) = markov {
+            check_markov(k, order)?;
+        }
+        let opts = CountOptions::new(max_ambiguous, masking)?;
+        let seeds = check_seeds(seeds)?;
+        let norms = feature_norms(norm);
         let qq=  py.allow_threads(move || {
         let mut rng = rand::thread_rng();
 
 
         let to_sample = (0..n)
-                .map(|_| {
-                    let file_idx =rng.gen_range(0..self.file_contents.len());
-                    let contig_idx = self.weight_index[file_idx].sample(&mut rng);
-                    let start_pos =  rng.gen_range(0..self.get_contig_size_unch(file_idx, contig_idx)- contig_sample_size);
-                    (file_idx,contig_idx,start_pos)
-                })
-                .collect::<Vec<(usize,usize,usize)>>();
+                .map(|_| rng.gen_range(0..self.file_contents.len()))
+                .collect::<Vec<usize>>();
+
+        // samples with invalid windows are redrawn, soft-masked windows don't count for that (see unmasked_invalid_windows)
+        let features = |ctg: &[u8], file_idx: usize| {
+            let rymers = find_rymers_bytes(ctg, &norms[5..], opts);
+            let invalid_count = unmasked_invalid_windows(ctg, rymers.5, opts);
+            let counts = contig_2_frac_nmer_counts_bytes(ctg, opts);
+            (nmer_distrs_from_counts(counts, &norms[..5]), rymers, file_idx, markov.map(|(k, order)| markov_ratios(ctg.iter().copied(), k, order)), invalid_count,
+             quotients.then(|| nmer_quotients(&counts)), seeds.iter().map(|seed| seed_distr(ctg, seed, opts)).collect::<Vec<_>>(),
+             masked_fraction.then(|| lowercase_fraction(ctg)))
+        };
 
         let pre_tens = to_sample.par_iter()
-                .map(| (file_idx,contig_idx,start_pos)|  (self.get_contig_slice(*file_idx,*contig_idx,*start_pos, contig_sample_size), *file_idx))
-                .map(|(ctg, file_idx)| (contig_2_nmer_distrs_bytes(ctg), find_rymers_bytes(ctg), file_idx) )
-                .map( |mut x| { // FIXME revert to 100?
-                    while   x.1.5 >= 1 {
-                        let mut rng = rand::thread_rng();
-                        let file_idx = x.2;
-                        let contig_idx = self.weight_index[file_idx].sample(&mut rng);// rng.gen_range(0..self.get_num_contig_unch(file_idx));
-                        let start_pos =  rng.gen_range(0..self.get_contig_size_unch(file_idx, contig_idx)- contig_sample_size);
-                        let ctg = self.get_contig_slice(file_idx,contig_idx,start_pos, contig_sample_size);
-                        x = (contig_2_nmer_distrs_bytes(ctg), find_rymers_bytes(ctg), file_idx) ;
-                    }
-                    x
-                })
-                .collect::<Vec<_>>();
+                .map(|&file_idx| self.draw_sample(file_idx, contig_sample_size, |ctg| features(ctg, file_idx), |x| x.4 >= 1))
+                .collect::<Option<Vec<_>>>()?;
 
         let pre_5mers = pre_tens
                                 .par_iter()
@@ -1523,7 +3559,10 @@ impl FastaDataBase {
         
         let pre_l4n1mers = pre_tens
                                 .par_iter()
-                                .flat_map_iter(|i| i.0.1)
+                                .flat_map_iter(|i| match &i.3 {
+                                    Some(ratios) => ratios.clone(),
+                                    None => i.0.1.to_vec(),
+                                })
                                 .collect::<Vec<_>>();
 
             
@@ -1578,6 +3617,11 @@ impl FastaDataBase {
                                 .map(|i| i.2)
                                 .collect::<Vec<_>>();
 
+        let pre_quotients = quotients.then(|| quotient_blocks(&pre_tens.iter().filter_map(|i| i.5).collect::<Vec<_>>()));
+        let pre_seeds = (0..seeds.len())
+                                .map(|s| pre_tens.par_iter().flat_map_iter(|i| i.6[s].iter().copied()).collect::<Vec<f32>>())
+                                .collect::<Vec<_>>();
+        let pre_masked = masked_fraction.then(|| pre_tens.iter().filter_map(|i| i.7).collect::<Vec<f32>>());
   
         let valids = pre_tens
                                 .par_iter()
@@ -1599,7 +3643,7 @@ impl FastaDataBase {
                                 //     label.into_pyarray(py)
                                 // )
 
-                                (
+                                Some((
                                     pre_5mers,
                                     pre_l4n1mers,
                                     pre_3mers,
@@ -1611,34 +3655,118 @@ impl FastaDataBase {
                                     pre_7mers,
                                     pre_6mers,
                                     label,
-                                ) });
-
-        (qq.0.into_pyarray(py),qq.1.into_pyarray(py),qq.2.into_pyarray(py),qq.3.into_pyarray(py),qq.4.into_pyarray(py),qq.5.into_pyarray(py),qq.6.into_pyarray(py),qq.7.into_pyarray(py),qq.8.into_pyarray(py),qq.9.into_pyarray(py),qq.10.into_pyarray(py))
+                                    pre_quotients,
+                                    pre_seeds,
+                                    pre_masked,
+                                )) }).ok_or_else(|| sample_draws_error(contig_sample_size))?;
+
+        // built as a list, a tuple with the quotient blocks is longer than pyo3 converts
+        let mut outputs = [qq.0, qq.1, qq.2, qq.3, qq.4, qq.5, qq.6, qq.7, qq.8, qq.9]
+                                .into_iter()
+                                .chain(qq.11.into_iter().flatten())
+                                .chain(qq.12)
+                                .chain(qq.13)
+                                .map(|x| x.into_pyarray(py).into_py(py))
+                                .collect::<Vec<PyObject>>();
+        outputs.push(qq.10.into_pyarray(py).into_py(py));
+        Ok(PyTuple::new(py, outputs).into_py(py))
         //unimplemented!();
         
         // PyArray::from_slice(py, &pre_5mers)
         
     }
 
+    /// One FracMinHash Sketch per genome, in the same order as the labels from `sample`. k mers don't span contigs.
+    #[args(k = "31", scaled = "1000")]
+    fn sketch(&self, py: Python, k: usize, scaled: u64) -> PyResult<Vec<Sketch>> {
+        check_sketch_args(k, scaled)?;
+        Ok(py.allow_threads(|| {
+            (0..self.contigs_start.len())
+                .into_par_iter()
+                .map(|file_idx| {
+                    let contigs = (0..self.get_num_contig_unch(file_idx))
+                            .map(|contig_idx| self.get_contig_slice(file_idx, contig_idx, 0, self.get_contig_size_unch(file_idx, contig_idx)));
+                    Sketch::from_contigs(contigs, k, scaled)
+                })
+                .collect()
+        }))
+    }
+
+    /// Same draws as `sample`, but returns the raw kmer counts (see CountMatrices) instead of normalized distributions, plus the labels.
+    /// stranded = True counts every kmer instead of merging it with its reverse compliment (see stranded_counts).
+    /// masking = "skip" drops windows with soft-masked (lowercase) bases.
+    #[args(stranded = "false", masking = "\"all\"")]
+    fn sample_counts<'py>(&'py self, py: Python<'py>, n: usize, contig_sample_size: usize, stranded: bool, masking: &str) -> PyResult<(CountMatrices<'py>, &'py PyArray1<usize>)> {
+        let opts = CountOptions::new(None, masking)?;
+        let (counts, label) = py.allow_threads(move || {
+            let mut rng = rand::thread_rng();
+
+            let to_sample = (0..n)
+                    .map(|_| rng.gen_range(0..self.file_contents.len()))
+                    .collect::<Vec<usize>>();
+
+            // the same draws as sample: redrawn while a window is invalid, soft-masked windows aside
+            let counts = to_sample.par_iter()
+                    .map(|&file_idx| {
+                        self.draw_sample(file_idx, contig_sample_size,
+                                         |ctg| {
+                                             let counts = block_counts(ctg.iter().map(|&b| opts.mask(b)), stranded);
+                                             let invalid_count = unmasked_invalid_windows(ctg, counts.2, opts);
+                                             (counts, invalid_count)
+                                         },
+                                         |x| x.1 >= 1).map(|x| x.0)
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+
+            Some((counts, to_sample))
+        }).ok_or_else(|| sample_draws_error(contig_sample_size))?;
+
+        Ok((count_matrices(py, &counts, stranded), label.into_pyarray(py)))
+    }
+
         
     
 }
 
 
-    #[pyfn(m)]
+    /// Raw counts of a single contig, as one row count matrices (see CountMatrices).
+    #[pyfn(m, contig, stranded = "false", masking = "\"all\"")]
+    #[pyo3(name = "find_single_counts")]
+    pub fn find_single_counts<'py>(py: Python<'py>, contig: &str, stranded: bool, masking: &str) -> PyResult<CountMatrices<'py>> {
+        let opts = CountOptions::new(None, masking)?;
+        let counts = py.allow_threads(|| block_counts(contig.bytes().filter(|&b| b != b'\n').map(|b| opts.mask(b)), stranded));
+        Ok(count_matrices(py, &[counts], stranded))
+    }
+
+    /// markov = (k, order) fills the third ("l4n1") output with markov_ratios instead of 4 mers.
+    /// max_ambiguous = m gives windows with up to m ambiguous IUPAC bases fractional counts (see add_ambiguous_windows).
+    /// masking = "skip" drops windows with soft-masked (lowercase) bases.
+    #[pyfn(m, contig, norm = "None", markov = "None", max_ambiguous = "None", masking = "\"all\"")]
     #[pyo3(name = "find_single")]
-    pub fn find_single<'py>(py: Python<'py>, contig: &str) -> (&'py PyArray1<f32>,&'py PyArray1<f32>,&'py PyArray1<f32>,&'py PyArray1<f32>,&'py PyArray1<f32>,&'py PyArray1<f32>, &'py PyArray1<f32>,&'py PyArray1<f32>,&'py PyArray1<f32>,&'py PyArray1<f32>,&'py PyArray1<f32>,usize ){
-        let (res,res2) = py.allow_threads(||( contig_2_nmer_distrs(contig),  find_rymers(contig)   ));
-        return (PyArray::from_slice(py, &res.1), PyArray::from_slice(py, &res.0), PyArray::from_slice(py, &res.2), PyArray::from_slice(py, &res.3),PyArray::from_slice(py, &res.4),PyArray::from_slice(py, &res.5),
-                PyArray::from_slice(py, &res2.0) ,PyArray::from_slice(py, &res2.1) ,PyArray::from_slice(py, &res2.2), PyArray::from_slice(py, &res2.3),PyArray::from_slice(py, &res2.4),   res2.5);
+    pub fn find_single<'py>(py: Python<'py>, contig: &str, norm: Option<PyRef<FeatureNormalization>>, markov: Option<(usize, usize)>, max_ambiguous: Option<usize>, masking: &str) -> PyResult<SingleDistributions<'py>> {
+        if let Some((k, order)) = markov {
+            check_markov(k, order)?;
+        }
+        let opts = CountOptions::new(max_ambiguous, masking)?;
+        let norms = feature_norms(norm);
+        let (res,res2,ratios) = py.allow_threads(||( contig_2_nmer_distrs(contig, &norms[..5], opts),  find_rymers(contig, &norms[5..], opts),
+                                                    markov.map(|(k, order)| markov_ratios(contig.bytes().filter(|&c| c != b'\n'), k, order))   ));
+        let l4n1 = match &ratios {
+            Some(ratios) => PyArray::from_slice(py, ratios),
+            None => PyArray::from_slice(py, &res.2),
+        };
+        Ok((PyArray::from_slice(py, &res.1), PyArray::from_slice(py, &res.0), l4n1, PyArray::from_slice(py, &res.3),PyArray::from_slice(py, &res.4),PyArray::from_slice(py, &res.5),
+                PyArray::from_slice(py, &res2.0) ,PyArray::from_slice(py, &res2.1) ,PyArray::from_slice(py, &res2.2), PyArray::from_slice(py, &res2.3),PyArray::from_slice(py, &res2.4),   res2.5))
     }
 
 
 
 
-    #[pyfn(m)]
+    #[pyfn(m, contigs, sample_size, norm = "None", max_ambiguous = "None", masking = "\"all\"")]
     #[pyo3(name = "sampling")]
-    pub fn sampling<'py>(py: Python<'py>, contigs: Vec<&str>, sample_size: usize) -> (&'py PyArray1<f32>,&'py PyArray1<f32>,&'py PyArray1<f32>,&'py PyArray1<f32>,&'py PyArray1<f32>,&'py PyArray1<f32>, &'py PyArray1<usize>) {
+    pub fn sampling<'py>(py: Python<'py>, contigs: Vec<&str>, sample_size: usize, norm: Option<PyRef<FeatureNormalization>>, max_ambiguous: Option<usize>, masking: &str) -> PyResult<SampledDistributions<'py>> {
+        let opts = CountOptions::new(max_ambiguous, masking)?;
+        let norms = feature_norms(norm);
         let qqq=  py.allow_threads(||{
        
         let pre_tens = contigs
@@ -1653,7 +3781,7 @@ impl FastaDataBase {
         let pre_tens = contigs
                 .par_iter()
                 .enumerate()
-                .map(|(a,&ctg)| contig_2_nmer_distrs(ctg))
+                .map(|(_, &ctg)| contig_2_nmer_distrs(ctg, &norms[..5], opts))
                 .collect::<Vec<_>>();
                 //dbg!("here6");
 
@@ -1703,518 +3831,1757 @@ impl FastaDataBase {
             valids
         )
     });
-    return    (qqq.0.into_pyarray(py),qqq.1.into_pyarray(py),qqq.2.into_pyarray(py),qqq.3.into_pyarray(py),qqq.4.into_pyarray(py),qqq.5.into_pyarray(py), qqq.6.into_pyarray(py));
+    Ok((qqq.0.into_pyarray(py),qqq.1.into_pyarray(py),qqq.2.into_pyarray(py),qqq.3.into_pyarray(py),qqq.4.into_pyarray(py),qqq.5.into_pyarray(py), qqq.6.into_pyarray(py)))
     }
 
-    #[pyfn(m)]
+    /// max_ambiguous = m gives windows with up to m ambiguous IUPAC bases fractional counts (see add_ambiguous_windows).
+    /// masking = "skip" drops windows with soft-masked (lowercase) bases.
+    /// quotients = True adds the quotiented 5,4,3,2 mer blocks (see nmer_quotients) after the RY 6 mers, before the names.
+    /// seeds = ["11011", ...] adds one L1 normed spaced seed block per mask (see seed_distr) after those, columns as kmer_labels(number of 1s).
+    /// A fastq file (starting with '@') gives a row per read; min_quality = q masks bases with a Phred quality below q.
+    /// batch_size = n streams the file, reading and counting about n bytes of records at a time instead of the whole file
+    /// at once, so memory no longer grows with the file (see for_each_record_batch). The output is the same.
+    /// contig_stats = True adds the stats block of find_contig_stats (with homopolymer_len) after the seed blocks, so the
+    /// file is only read once.
+    /// masked_fraction = True adds the fraction of each contig's bases that are lowercase (as find_masked_counts) after that.
+    /// markov = (k, order) fills the 4 mer block with markov_ratios instead, the same features as sample(markov = ...).
+    /// contig_lens, the blocks and contig_names all have a row per contig of at least mini_size bases.
+    #[pyfn(m, contig_file, mini_size, norm = "None", max_ambiguous = "None", masking = "\"all\"", quotients = "false", seeds = "None", min_quality = "None", batch_size = "None", contig_stats = "false", homopolymer_len = "5", masked_fraction = "false", markov = "None")]
     #[pyo3(name = "find_nMer_distributions")]
-    pub fn find_nMer_distributions<'py>(py: Python<'py>, contig_file: &str, mini_size: usize) -> (Vec<usize>,&'py PyArray1<f32>,&'py PyArray1<f32>,&'py PyArray1<f32>,&'py PyArray1<f32>,&'py PyArray1<f32>, &'py PyArray1<f32>, &'py PyArray1<f32>, &'py PyArray1<f32>, &'py PyArray1<f32>,&'py PyArray1<f32>, Vec<String>) {
+    #[allow(clippy::too_many_arguments)]
+    pub fn find_nmer_distributions(py: Python, contig_file: &str, mini_size: usize, norm: Option<PyRef<FeatureNormalization>>, max_ambiguous: Option<usize>, masking: &str, quotients: bool, seeds: Option<Vec<String>>, min_quality: Option<u8>, batch_size: Option<usize>, contig_stats: bool, homopolymer_len: usize, masked_fraction: bool, markov: Option<(usize, usize)>) -> PyResult<PyObject> {
+        if let Some((k, order)) = markov {
+            check_markov(k, order)?;
+        }
+        let opts = CountOptions::new(max_ambiguous, masking)?;
+        let extra = ExtraBlocks { markov, quotients, seeds: check_seeds(seeds)?, contig_stats: contig_stats.then_some(homopolymer_len), masked_fraction };
         //rayon::ThreadPoolBuilder::new().num_threads(32).build_global().unwrap();
         //rayon::ThreadPoolBuilder::new().num_threads(64).build_global().unwrap();
-        let file_info = fs::metadata(contig_file).unwrap();
-        let file_size = file_info.len();
-
-        let mut file = File::open(contig_file).unwrap();
-
-        //read using multiple file handles for faster spead
-        let mut read_buffer: Vec<u8> = Vec::new();
-        par_read(&mut file, &mut read_buffer, file_size);
-        //dbg!("read done");
-        let mut decom_buffer: Vec<u8> = Vec::new();
-
-        //covert to string representation for easy tooling (slightly slower, but easy)
-        let string_slice_rep: &str = if contig_file.len() >3 && b'z'==contig_file.as_bytes()[contig_file.len()-1] {
-            let mut gz = GzDecoder::new(&read_buffer[..]);
-            gz.read_to_end(&mut  decom_buffer).unwrap();
-            std::str::from_utf8(&decom_buffer[..]).unwrap() 
+        let norms = feature_norms(norm);
+
+        let batch = if let Some(batch_size) = batch_size {
+            // only batch_size bytes of the file are held at a time, the outputs are appended batch by batch
+            py.allow_threads(|| {
+                open_contig_file(contig_file).and_then(|mut reader| {
+                    nmer_distributions(&mut reader, batch_size, min_quality, mini_size, &norms, opts, &extra)
+                })
+            })
         } else {
-            std::str::from_utf8(&read_buffer[..]).unwrap() 
+            // decompressed if needed, see Compression
+            let read_buffer = load_contig_file(py, contig_file)?;
+            //dbg!("read done");
+            py.allow_threads(|| {
+                if is_fastq(&read_buffer) {
+                    nmer_distributions(&mut &read_buffer[..], usize::MAX, min_quality, mini_size, &norms, opts, &extra)
+                } else {
+                    // the records are split in parallel straight out of the buffer, no copy of it is made
+                    Ok(nmer_batch(&fasta_records(&read_buffer), mini_size, &norms, opts, &extra))
+                }
+            })
+        }.map_err(|e| contig_file_error(contig_file, e))?;
+
+        // built as a list, a tuple with the quotient blocks is longer than pyo3 converts
+        let mut outputs = vec![batch.contig_lens.into_py(py)];
+        outputs.extend(batch.blocks.into_iter().map(|x| x.into_pyarray(py).into_py(py)));
+        outputs.push(batch.contig_names.into_py(py));
+        Ok(PyTuple::new(py, outputs).into_py(py))
+    }
+
+    /// One pooled profile per group of contigs, e.g. the bins of write_fasta_bins or the contigs of reference genomes.
+    /// contig_names[i] belongs to group group_labels[i]; contigs of the fasta that are not listed are ignored, and a contig
+    /// can only be listed once.
+    /// The raw counts of all contigs in a group are summed and then normalized like find_nMer_distributions.
+    /// Returns (groups (sorted labels), group_lens (bases), the same 10 blocks as find_nMer_distributions with a row per group).
+    #[pyfn(m, contig_names, group_labels, contig_file, norm = "None", max_ambiguous = "None", masking = "\"all\"")]
+    #[pyo3(name = "find_group_distributions")]
+    pub fn find_group_distributions(py: Python, contig_names: Vec<String>, group_labels: Vec<usize>, contig_file: &str, norm: Option<PyRef<FeatureNormalization>>, max_ambiguous: Option<usize>, masking: &str) -> PyResult<PyObject> {
+        if contig_names.len() != group_labels.len() {
+            return Err(PyValueError::new_err(format!("got {} contig names but {} group labels", contig_names.len(), group_labels.len())));
+        }
+        let opts = CountOptions::new(max_ambiguous, masking)?;
+        let norms = feature_norms(norm);
+
+        let contigs = load_contigs(py, contig_file, 0)?;
+        let contig_idx = contigs.iter().enumerate().map(|(i, (name, _))| (&name[..], i)).collect::<HashMap<&str, usize>>();
+
+        // group label -> contigs in the group
+        let mut members: BTreeMap<usize, Vec<&[u8]>> = BTreeMap::new();
+        let mut listed = vec![false; contigs.len()];
+        for (name, &label) in contig_names.iter().zip(&group_labels) {
+            let &i = contig_idx.get(&name[..]).ok_or_else(|| PyValueError::new_err(format!("contig {:?} is not in {}", name, contig_file)))?;
+            // counting a contig twice would weigh it double, or put it in two groups
+            if std::mem::replace(&mut listed[i], true) {
+                return Err(PyValueError::new_err(format!("contig {:?} is listed more than once", name)));
+            }
+            members.entry(label).or_default().push(&contigs[i].1);
+        }
+
+        let (groups, group_lens, blocks) = py.allow_threads(|| {
+            let distrs = group_distrs(&members.values().collect::<Vec<_>>(), &norms, opts);
+            let blocks: [Vec<f32>; 10] = [
+                distrs.iter().flat_map(|d| d.0.0).collect(), distrs.iter().flat_map(|d| d.0.1).collect(),
+                distrs.iter().flat_map(|d| d.0.3).collect(), distrs.iter().flat_map(|d| d.0.4).collect(),
+                distrs.iter().flat_map(|d| d.0.5).collect(), distrs.iter().flat_map(|d| d.1.0).collect(),
+                distrs.iter().flat_map(|d| d.1.1).collect(), distrs.iter().flat_map(|d| d.1.2).collect(),
+                distrs.iter().flat_map(|d| d.1.3).collect(), distrs.iter().flat_map(|d| d.1.4).collect(),
+            ];
+            let group_lens = members.values().map(|ctgs| ctgs.iter().map(|ctg| ctg.len()).sum::<usize>()).collect::<Vec<_>>();
+            (members.keys().copied().collect::<Vec<_>>(), group_lens, blocks)
+        });
+
+        let mut outputs = vec![groups.into_py(py), group_lens.into_py(py)];
+        outputs.extend(blocks.into_iter().map(|x| x.into_pyarray(py).into_py(py)));
+        Ok(PyTuple::new(py, outputs).into_py(py))
+    }
+
+    /// Raw kmer counts for every contig of at least mini_size bases in a (optionally compressed) fasta.
+    /// Returns (contig_lens, count matrices (see CountMatrices), contig_names).
+    /// stranded = True counts every kmer instead of merging it with its reverse compliment (see stranded_counts).
+    /// masking = "skip" drops windows with soft-masked (lowercase) bases.
+    #[pyfn(m, contig_file, mini_size, stranded = "false", masking = "\"all\"")]
+    #[pyo3(name = "find_nMer_counts")]
+    pub fn find_nmer_counts<'py>(py: Python<'py>, contig_file: &str, mini_size: usize, stranded: bool, masking: &str) -> PyResult<(Vec<usize>, CountMatrices<'py>, Vec<String>)> {
+        let opts = CountOptions::new(None, masking)?;
+        let contigs = load_contigs(py, contig_file, mini_size)?;
+        let (contig_lens, counts, contig_names) = py.allow_threads(|| {
+
+            let counts = contigs
+                    .par_iter()
+                    .map(|(_, ctg)| block_counts(ctg.iter().map(|&b| opts.mask(b)), stranded))
+                    .collect::<Vec<_>>();
+
+            let contig_lens = contigs.iter().map(|(_, ctg)| ctg.len()).collect::<Vec<_>>();
+            let contig_names = contigs.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+            (contig_lens, counts, contig_names)
+        });
+
+        Ok((contig_lens, count_matrices(py, &counts, stranded), contig_names))
+    }
+
+    /// Codon usage, amino acid and dipeptide frequencies of every contig of at least mini_size bases (see contig_2_codon_distrs_bytes).
+    /// Returns (contig_lens, codons (n, 64), amino acids (n, 21), dipeptides (n, 400), contig_names). genetic_code is an NCBI
+    /// table number (see GENETIC_CODES), mode is "six_frame" or "orf" (only ORFs of at least min_orf_len codons).
+    #[pyfn(m, contig_file, mini_size, genetic_code = "11", mode = "\"six_frame\"", min_orf_len = "100")]
+    #[pyo3(name = "find_codon_distributions")]
+    pub fn find_codon_distributions<'py>(py: Python<'py>, contig_file: &str, mini_size: usize, genetic_code: usize, mode: &str, min_orf_len: usize) -> PyResult<CodonDistributions<'py>> {
+        let code = self::genetic_code(genetic_code)?;
+        let min_orf_len = check_orf_mode(mode, min_orf_len)?;
+
+        let contigs = load_contigs(py, contig_file, mini_size)?;
+        let (contig_lens, distrs, contig_names) = py.allow_threads(|| {
+
+            let distrs = contigs
+                    .par_iter()
+                    .map(|(_, ctg)| contig_2_codon_distrs_bytes(ctg, &code, min_orf_len))
+                    .collect::<Vec<_>>();
+
+            let contig_lens = contigs.iter().map(|(_, ctg)| ctg.len()).collect::<Vec<_>>();
+            let contig_names = contigs.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+            (contig_lens, distrs, contig_names)
+        });
+
+        Ok((contig_lens,
+            freqs_matrix(py, distrs.iter().map(|x| x.0)),
+            freqs_matrix(py, distrs.iter().map(|x| x.1)),
+            freqs_matrix(py, distrs.iter().map(|x| x.2)),
+            contig_names))
+    }
+
+    /// Codon usage, amino acid and dipeptide frequencies of a single contig.
+    #[pyfn(m, contig, genetic_code = "11", mode = "\"six_frame\"", min_orf_len = "100")]
+    #[pyo3(name = "find_single_codons")]
+    pub fn find_single_codons<'py>(py: Python<'py>, contig: &str, genetic_code: usize, mode: &str, min_orf_len: usize) -> PyResult<(&'py PyArray1<f32>, &'py PyArray1<f32>, &'py PyArray1<f32>)> {
+        let code = self::genetic_code(genetic_code)?;
+        let min_orf_len = check_orf_mode(mode, min_orf_len)?;
+        let contig = contig.bytes().filter(|&b| b != b'\n').collect::<Vec<u8>>();
+        let (codons, aas, dipeptides) = py.allow_threads(|| contig_2_codon_distrs_bytes(&contig, &code, min_orf_len));
+        Ok((PyArray::from_slice(py, &codons), PyArray::from_slice(py, &aas), PyArray::from_slice(py, &dipeptides)))
+    }
+
+    /// FracMinHash Sketch of a single contig.
+    #[pyfn(m, contig, k = "31", scaled = "1000")]
+    #[pyo3(name = "sketch_contig")]
+    pub fn sketch_contig(py: Python, contig: &str, k: usize, scaled: u64) -> PyResult<Sketch> {
+        check_sketch_args(k, scaled)?;
+        let contig = contig.bytes().filter(|&b| b != b'\n').collect::<Vec<u8>>();
+        Ok(py.allow_threads(|| Sketch::from_contigs(std::iter::once(&contig[..]), k, scaled)))
+    }
+
+    /// One FracMinHash Sketch of all contigs of at least mini_size bases in a (optionally compressed) fasta, e.g. a genome or a bin.
+    #[pyfn(m, contig_file, k = "31", scaled = "1000", mini_size = "0")]
+    #[pyo3(name = "sketch_fasta")]
+    pub fn sketch_fasta(py: Python, contig_file: &str, k: usize, scaled: u64, mini_size: usize) -> PyResult<Sketch> {
+        check_sketch_args(k, scaled)?;
+        let contigs = load_contigs(py, contig_file, mini_size)?;
+        Ok(py.allow_threads(|| {
+            let parts = contigs.par_iter()
+                               .map(|(_, ctg)| Sketch::from_contigs(std::iter::once(&ctg[..]), k, scaled).hashes)
+                               .collect::<Vec<_>>();
+            let mut hashes = parts.concat();
+            hashes.sort_unstable();
+            hashes.dedup();
+            Sketch { k, scaled, hashes }
+        }))
+    }
+
+    /// A FracMinHash Sketch for every contig of at least mini_size bases. Returns (contig_names, sketches).
+    #[pyfn(m, contig_file, mini_size, k = "31", scaled = "1000")]
+    #[pyo3(name = "sketch_contigs")]
+    pub fn sketch_contigs(py: Python, contig_file: &str, mini_size: usize, k: usize, scaled: u64) -> PyResult<(Vec<String>, Vec<Sketch>)> {
+        check_sketch_args(k, scaled)?;
+        let contigs = load_contigs(py, contig_file, mini_size)?;
+        Ok(py.allow_threads(|| {
+            let sketches = contigs.par_iter()
+                                  .map(|(_, ctg)| Sketch::from_contigs(std::iter::once(&ctg[..]), k, scaled))
+                                  .collect::<Vec<_>>();
+            (contigs.into_iter().map(|(name, _)| name).collect(), sketches)
+        }))
+    }
+
+    /// (num_queries, num_references) matrix of metric ("jaccard", "containment" of the query in the reference, or "ani")
+    /// between every pair, e.g. all genomes against each other to dereplicate them, or bins against references.
+    #[pyfn(m, queries, references, metric = "\"ani\"")]
+    #[pyo3(name = "compare_sketches")]
+    pub fn compare_sketches<'py>(py: Python<'py>, queries: Vec<Sketch>, references: Vec<Sketch>, metric: &str) -> PyResult<&'py PyArray2<f64>> {
+        let metric = match metric {
+            "jaccard" => Sketch::jaccard_unch,
+            "containment" => Sketch::containment_unch,
+            "ani" => Sketch::ani_unch,
+            _ => return Err(PyValueError::new_err(format!("unknown metric {:?}, expected \"jaccard\", \"containment\" or \"ani\"", metric))),
         };
+        let mut sketches = queries.iter().chain(&references);
+        if let Some(first) = sketches.next() {
+            for sketch in sketches {
+                first.check_comparable(sketch)?;
+            }
+        }
+        let matrix = py.allow_threads(|| sketch_matrix(&queries, &references, metric));
+        Ok(matrix.into_pyarray(py).reshape([queries.len(), references.len()]).unwrap())
+    }
+
+    /// Sequence statistics of every contig of at least mini_size bases, in one parallel pass.
+    /// Returns (contig_lens, stats, skew_curves, contig_names). stats is (num_contigs, 10), see NUM_CONTIG_STATS for the columns.
+    /// With skew_window, skew_curves[i] is the (num_windows, 4) skew curve of contig i (see skew_curve), otherwise None.
+    #[pyfn(m, contig_file, mini_size, homopolymer_len = "5", skew_window = "None")]
+    #[pyo3(name = "find_contig_stats")]
+    pub fn find_contig_stats<'py>(py: Python<'py>, contig_file: &str, mini_size: usize, homopolymer_len: usize, skew_window: Option<usize>) -> PyResult<ContigStats<'py>> {
+        if skew_window == Some(0) {
+            return Err(PyValueError::new_err("skew_window must be positive"));
+        }
+
+        let contigs = load_contigs(py, contig_file, mini_size)?;
+        let (contig_lens, stats, curves, contig_names) = py.allow_threads(|| {
+
+            let (stats, curves) = contigs
+                    .par_iter()
+                    .map(|(_, ctg)| (contig_stats(ctg, homopolymer_len), skew_window.map(|w| skew_curve(ctg, w))))
+                    .unzip::<_, _, Vec<_>, Vec<_>>();
+
+            let contig_lens = contigs.iter().map(|(_, ctg)| ctg.len()).collect::<Vec<_>>();
+            let contig_names = contigs.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+            (contig_lens, stats, curves, contig_names)
+        });
+
+        let num_contigs = stats.len();
+        let stats = stats.into_iter().flatten().collect::<Vec<f32>>().into_pyarray(py).reshape([num_contigs, NUM_CONTIG_STATS]).unwrap();
+        let curves = skew_window.map(|_| {
+            curves.into_iter()
+                  .map(|curve| {
+                      let curve = curve.unwrap();
+                      let num_windows = curve.len();
+                      curve.into_iter().flatten().collect::<Vec<f32>>().into_pyarray(py).reshape([num_windows, 4]).unwrap()
+                  })
+                  .collect()
+        });
+        Ok((contig_lens, stats, curves, contig_names))
+    }
+
+    /// Profiles of windows along every contig of at least mini_size bases, `window` bases long every `step` bases.
+    /// Returns (contig_lens, profiles, coordinates, contig_names): profiles[i] is a (num_windows, 1712) matrix for contig i
+    /// with the find_nMer_distributions blocks side by side (5,4,3,2,1 mers, RY 10,9,8,7,6 mers), and coordinates[i] the
+    /// (num_windows, 2) start and end of each window. Contigs shorter than the window get no windows.
+    #[pyfn(m, contig_file, mini_size, window, step, norm = "None", max_ambiguous = "None", masking = "\"all\"")]
+    #[pyo3(name = "find_window_distributions")]
+    #[allow(clippy::too_many_arguments)]
+    pub fn find_window_distributions<'py>(py: Python<'py>, contig_file: &str, mini_size: usize, window: usize, step: usize, norm: Option<PyRef<FeatureNormalization>>, max_ambiguous: Option<usize>, masking: &str) -> PyResult<WindowDistributions<'py>> {
+        check_windows(window, step)?;
+        let opts = CountOptions::new(max_ambiguous, masking)?;
+        let norms = feature_norms(norm);
+
+        let contigs = load_contigs(py, contig_file, mini_size)?;
+        let (contig_lens, profiles, contig_names) = py.allow_threads(|| {
+
+            let profiles = window_profiles(&contigs.iter().map(|(_, ctg)| &ctg[..]).collect::<Vec<_>>(), window, step, &norms, opts);
+
+            let contig_lens = contigs.iter().map(|(_, ctg)| ctg.len()).collect::<Vec<_>>();
+            let contig_names = contigs.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+            (contig_lens, profiles, contig_names)
+        });
+
+        let (profiles, coords) = window_matrices(py, profiles);
+        Ok((contig_lens, profiles, coords, contig_names))
+    }
+
+    /// Window profiles of a single contig, (profiles, coordinates) as in find_window_distributions.
+    #[pyfn(m, contig, window, step, norm = "None", max_ambiguous = "None", masking = "\"all\"")]
+    #[pyo3(name = "find_single_windows")]
+    pub fn find_single_windows<'py>(py: Python<'py>, contig: &str, window: usize, step: usize, norm: Option<PyRef<FeatureNormalization>>, max_ambiguous: Option<usize>, masking: &str) -> PyResult<(&'py PyArray2<f32>, &'py PyArray2<usize>)> {
+        check_windows(window, step)?;
+        let opts = CountOptions::new(max_ambiguous, masking)?;
+        let norms = feature_norms(norm);
+        let contig = contig.bytes().filter(|&b| b != b'\n').collect::<Vec<u8>>();
+        let profiles = py.allow_threads(|| window_profiles(&[&contig[..]], window, step, &norms, opts));
+        let (mut profiles, mut coords) = window_matrices(py, profiles);
+        Ok((profiles.pop().unwrap(), coords.pop().unwrap()))
+    }
+
+    /// Raw kmer counts like find_nMer_counts, with windows that have a soft-masked (lowercase) base counted separately.
+    /// Returns (contig_lens, unmasked count matrices, masked count matrices, masked fraction, contig_names), where the
+    /// masked fraction is the fraction of each contig's bases that are lowercase.
+    #[pyfn(m, contig_file, mini_size, stranded = "false")]
+    #[pyo3(name = "find_masked_counts")]
+    pub fn find_masked_counts<'py>(py: Python<'py>, contig_file: &str, mini_size: usize, stranded: bool) -> PyResult<MaskedCounts<'py>> {
+        let contigs = load_contigs(py, contig_file, mini_size)?;
+        let (contig_lens, unmasked, masked, masked_fraction, contig_names) = py.allow_threads(|| {
+
+            let counts = contigs
+                    .par_iter()
+                    .map(|(_, ctg)| masked_block_counts(ctg.iter().copied(), stranded))
+                    .collect::<Vec<_>>();
+
+            let contig_lens = contigs.iter().map(|(_, ctg)| ctg.len()).collect::<Vec<_>>();
+            let masked_fraction = counts.iter()
+                                        .zip(&contig_lens)
+                                        .map(|(x, &len)| x.2 as f32 / len.max(1) as f32)
+                                        .collect::<Vec<_>>();
+            let (unmasked, masked) = counts.into_iter().map(|x| (x.0, x.1)).unzip::<_, _, Vec<_>, Vec<_>>();
+            let contig_names = contigs.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+            (contig_lens, unmasked, masked, masked_fraction, contig_names)
+        });
+
+        Ok((contig_lens, count_matrices(py, &unmasked, stranded), count_matrices(py, &masked, stranded), masked_fraction.into_pyarray(py), contig_names))
+    }
+
+    /// Canonical kmer distributions for any set of k (1 <= k <= 12) from a (optionally compressed) fasta.
+    /// Returns (contig_lens, one flat array per k in the order given, contig_names); reshape array i to (-1, n_i)
+    /// where n_i is the number of canonical ks[i]-mers. For k <= 5 the column order is the same as find_nMer_distributions.
+    /// max_ambiguous and masking work as for find_nMer_distributions.
+    #[pyfn(m, contig_file, mini_size, ks, max_ambiguous = "None", masking = "\"all\"")]
+    #[pyo3(name = "find_kmer_distributions")]
+    pub fn find_kmer_distributions<'py>(py: Python<'py>, contig_file: &str, mini_size: usize, ks: Vec<usize>, max_ambiguous: Option<usize>, masking: &str) -> PyResult<KmerDistributions<'py>> {
+        check_canonical_ks(&ks)?;
+        let opts = CountOptions::new(max_ambiguous, masking)?;
+
+        let contigs = load_contigs(py, contig_file, mini_size)?;
+        let (contig_lens, distrs, contig_names) = py.allow_threads(|| {
+
+            let pre_tens = contigs
+                    .par_iter()
+                    .map(|(_, ctg)| contig_2_kmer_distrs_bytes(ctg, &ks, opts).0)
+                    .collect::<Vec<_>>();
+
+            let distrs = (0..ks.len())
+                    .map(|i| pre_tens
+                                .par_iter()
+                                .flat_map_iter(|x| x[i].iter().copied())
+                                .collect::<Vec<_>>())
+                    .collect::<Vec<_>>();
+
+            let contig_lens = contigs.iter().map(|(_, ctg)| ctg.len()).collect::<Vec<_>>();
+            let contig_names = contigs.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+            (contig_lens, distrs, contig_names)
+        });
+
+        Ok((contig_lens, distrs.into_iter().map(|x| x.into_pyarray(py)).collect(), contig_names))
+    }
+
+    /// Like the RY blocks of find_nMer_distributions (quotiented 10,9,8,7 mers and 6 mers, normalized the default way), for
+    /// any binary alphabet: "SW" (strong G/C vs weak A/T), "KM" (keto G/T vs amino A/C) or "RY", and any range of k from
+    /// k_min to k_max (1 <= k_min <= k_max <= 16): every k but k_min is quotiented by the k-1 mers.
+    /// Returns (contig_lens, ks, one (num_contigs, n) matrix per k in ks, contig_names), ks going from k_max down to k_min.
+    /// SW kmers are their own compliment, so the SW blocks of odd k are a bit bigger than the RY ones; binary_labels gives the columns.
+    #[pyfn(m, contig_file, mini_size, alphabet = "\"SW\"", k_min = "6", k_max = "10", max_ambiguous = "None", masking = "\"all\"")]
+    #[pyo3(name = "find_binary_distributions")]
+    #[allow(clippy::too_many_arguments)]
+    pub fn find_binary_distributions<'py>(py: Python<'py>, contig_file: &str, mini_size: usize, alphabet: &str, k_min: usize, k_max: usize, max_ambiguous: Option<usize>, masking: &str) -> PyResult<BinaryDistributions<'py>> {
+        let alphabet = BinaryAlphabet::new(alphabet)?;
+        let ks = binary_ks(k_min, k_max)?;
+        let opts = CountOptions::new(max_ambiguous, masking)?;
+        let norms = binary_norms(ks.len());
+
+        let contigs = load_contigs(py, contig_file, mini_size)?;
+        let (contig_lens, blocks, contig_names) = py.allow_threads(|| {
+
+            let distrs = contigs.par_iter()
+                                .map(|(_, ctg)| binary_distrs(ctg, alphabet, &ks, &norms, opts).0)
+                                .collect::<Vec<_>>();
+            let blocks = (0..ks.len())
+                    .map(|i| distrs.iter().flat_map(|x| x[i].iter().copied()).collect::<Vec<f32>>())
+                    .collect::<Vec<_>>();
+
+            let contig_lens = contigs.iter().map(|(_, ctg)| ctg.len()).collect::<Vec<_>>();
+            let contig_names = contigs.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+            (contig_lens, blocks, contig_names)
+        });
+
+        let matrices = blocks.into_iter()
+                             .zip(&ks)
+                             .map(|(block, &k)| {
+                                 let width = alphabet.block_size(k);
+                                 block.into_pyarray(py).reshape([contig_lens.len(), width]).unwrap()
+                             })
+                             .collect();
+        Ok((contig_lens, ks, matrices, contig_names))
+    }
+
+    /// find_binary_distributions for a single contig: (ks, one block per k, the number of invalid k_max base windows).
+    #[pyfn(m, contig, alphabet = "\"SW\"", k_min = "6", k_max = "10", max_ambiguous = "None", masking = "\"all\"")]
+    #[pyo3(name = "find_single_binary")]
+    pub fn find_single_binary<'py>(py: Python<'py>, contig: &str, alphabet: &str, k_min: usize, k_max: usize, max_ambiguous: Option<usize>, masking: &str) -> PyResult<SingleBinaryDistributions<'py>> {
+        let alphabet = BinaryAlphabet::new(alphabet)?;
+        let ks = binary_ks(k_min, k_max)?;
+        let opts = CountOptions::new(max_ambiguous, masking)?;
+        let contig = contig.bytes().filter(|&b| b != b'\n').collect::<Vec<u8>>();
+        let (distrs, invalid_count) = py.allow_threads(|| binary_distrs(&contig, alphabet, &ks, &binary_norms(ks.len()), opts));
+        Ok((ks, distrs.iter().map(|x| PyArray::from_slice(py, x)).collect(), invalid_count))
+    }
+
+    /// The columns of find_binary_distributions for alphabet and the same k range, like the RY blocks of feature_labels.
+    #[pyfn(m, alphabet = "\"SW\"", k_min = "6", k_max = "10")]
+    #[pyo3(name = "binary_labels")]
+    pub fn binary_labels(alphabet: &str, k_min: usize, k_max: usize) -> PyResult<Vec<Vec<Vec<String>>>> {
+        let alphabet = BinaryAlphabet::new(alphabet)?;
+        Ok(binary_ks(k_min, k_max)?.into_iter().map(|k| table_labels(alphabet.table(k), k, alphabet.letters())).collect())
+    }
+
+    /// For every feature block of find_nMer_distributions (5,4,3,2,1 mers, then RY 10,9,8,7,6 mers), the kmers counted in
+    /// each column: labels[block][column] is the kmer and its reverse compliment (just one if it's its own reverse compliment),
+    /// RY mers are written with R and Y. With stranded=True, it's the single kmer of each column of the stranded counts.
+    #[pyfn(m, stranded = "false")]
+    #[pyo3(name = "feature_labels")]
+    pub fn feature_labels(stranded: bool) -> Vec<Vec<Vec<String>>> {
+        let tables: [(&[u32], usize, &[u8]); NUM_FEATURE_BLOCKS] = [(&FIVEMERTABLE, 5, NUCLEOTIDES), (&FOURMERTABLE, 4, NUCLEOTIDES),
+                (&THREEMERTABLE, 3, NUCLEOTIDES), (&TWOMERTABLE, 2, NUCLEOTIDES), (&ONEMERTABLE, 1, NUCLEOTIDES), (&RY10TBL, 10, RY_LETTERS),
+                (&RY9TBL, 9, RY_LETTERS), (&RY8TBL, 8, RY_LETTERS), (&RY7TBL, 7, RY_LETTERS), (&RY6TBL, 6, RY_LETTERS)];
+        tables.iter()
+              .map(|&(tbl, k, alphabet)| {
+                  if stranded {
+                      (0..tbl.len()).map(|code| vec![kmer_string(code as u64, k, alphabet)]).collect()
+                  } else {
+                      table_labels(tbl, k, alphabet)
+                  }
+              })
+              .collect()
+    }
+
+    /// The kmers counted in each column of find_kmer_distributions for k (1 <= k <= 12), same layout as a block of feature_labels.
+    #[pyfn(m)]
+    #[pyo3(name = "kmer_labels")]
+    pub fn kmer_labels(k: usize) -> PyResult<Vec<Vec<String>>> {
+        check_canonical_ks(&[k])?;
+        Ok(table_labels(canonical_table(k), k, NUCLEOTIDES))
+    }
+
+    /// Canonical kmer distributions of a single contig for each k in ks, plus the number of invalid windows of the largest k.
+    #[pyfn(m, contig, ks, max_ambiguous = "None", masking = "\"all\"")]
+    #[pyo3(name = "find_single_kmers")]
+    pub fn find_single_kmers<'py>(py: Python<'py>, contig: &str, ks: Vec<usize>, max_ambiguous: Option<usize>, masking: &str) -> PyResult<(Vec<&'py PyArray1<f32>>, usize)> {
+        check_canonical_ks(&ks)?;
+        let opts = CountOptions::new(max_ambiguous, masking)?;
+        let contig = contig.bytes().filter(|&x| x != b'\n').collect::<Vec<u8>>();
+        let (distrs, invalid_count) = py.allow_threads(|| contig_2_kmer_distrs_bytes(&contig, &ks, opts));
+        Ok((distrs.iter().map(|x| PyArray::from_slice(py, x)).collect(), invalid_count))
+    }
+
+    /// Markov normalized canonical k mer features (observed / expected under an order `order` Markov model of each contig)
+    /// from a (optionally compressed) fasta. Returns (contig_lens, flat array, contig_names); reshape the array to
+    /// (-1, number of canonical k mers). k = 4, order = 0 gives the "l4n1" feature.
+    #[pyfn(m)]
+    #[pyo3(name = "find_markov_distributions")]
+    pub fn find_markov_distributions<'py>(py: Python<'py>, contig_file: &str, mini_size: usize, k: usize, order: usize) -> PyResult<(Vec<usize>, &'py PyArray1<f32>, Vec<String>)> {
+        check_markov(k, order)?;
+
+        let contigs = load_contigs(py, contig_file, mini_size)?;
+        let (contig_lens, ratios, contig_names) = py.allow_threads(|| {
+
+            let ratios = contigs
+                    .par_iter()
+                    .flat_map_iter(|(_, ctg)| markov_ratios(ctg.iter().copied(), k, order))
+                    .collect::<Vec<_>>();
+
+            let contig_lens = contigs.iter().map(|(_, ctg)| ctg.len()).collect::<Vec<_>>();
+            let contig_names = contigs.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+            (contig_lens, ratios, contig_names)
+        });
+
+        Ok((contig_lens, ratios.into_pyarray(py), contig_names))
+    }
+
+    /// Markov normalized canonical k mer features of a single contig.
+    #[pyfn(m)]
+    #[pyo3(name = "find_single_markov")]
+    pub fn find_single_markov<'py>(py: Python<'py>, contig: &str, k: usize, order: usize) -> PyResult<&'py PyArray1<f32>> {
+        check_markov(k, order)?;
+        let ratios = py.allow_threads(|| markov_ratios(contig.bytes().filter(|&x| x != b'\n'), k, order));
+        Ok(ratios.into_pyarray(py))
+    }
+
+    #[pyfn(m)]
+    #[pyo3(name = "acumen")]
+    pub fn acumen<'py>(py: Python<'py>, filepath: String,  names: Vec<String>, src: Vec<usize>, dst: Vec<usize>, weights: Vec<f32>) -> Vec<isize>{
+        let markers = find_markers(&filepath, &names);
+        let (root_idx, tree_vec) = build_dendrogram(weights, src, dst, markers); 
+        let root_idx = tree_vec.len() - 1;
+        let (contig_idxs, mut cluster_start) = traverse_tree(&tree_vec, root_idx as u32);
+        cluster_start.push(contig_idxs.len());
+        let mut result = vec![-1;names.len()];
 
-        //dbg!("here1");
 
+        for (cluster_num, window) in cluster_start.windows(2)
+                                .enumerate() {
+            let start = window[0];
+            let end = window[1];
+            for contig_idx  in contig_idxs[start..end].iter() {
+                result[*contig_idx as usize] = cluster_num as isize;
+            }
+        }
+        result
+
+    }
 
-       { };
+    #[pyfn(m)]
+    #[pyo3(name = "write_fasta_bins")]
+    pub fn write_fasta_bins<'py>(py: Python<'py>, contig_name: Vec<String>, bin_number: Vec<usize> ,src_contig_file: &str, outfolder: &str) -> PyResult<usize> {
 
+        // decompressed if needed, see Compression
+        let read_buffer = load_contig_file(py, src_contig_file)?;
+        if is_fastq(&read_buffer) {
+            return Err(PyValueError::new_err(format!("{} is fastq, write_fasta_bins copies fasta records", src_contig_file)));
+        }
 
-        // split on new fasta name delimitor, skip first trivial
-        let lines = string_slice_rep.par_split('>').filter(|x| x.len() >= mini_size).collect::<Vec<_>>();
-        //dbg!("here2");]
+        let contig_name_map = fasta_records(&read_buffer)
+            .into_iter()
+            .map(|record| (record.name, record))
+            .collect::<HashMap<&[u8], FastaRecord>>();
         
 
-        let num_contigs = lines.len();
-        //dbg!("here3");
-
-        // init contig name, contig vec containers
-        let mut contig_names: Vec<String> = vec![String::new(); 0];
-        let mut contigs: Vec<&str> = vec!["Bad Contig Name"; 0];
-        //dbg!("here4");
-
-        // we divide into contig name and actual contigs
-        lines
-            .par_iter()
-            .enumerate()
-            .map(|(q,line)| {
-                let end_of_name_pos = line.find('\n').unwrap_or_else(|| {
-                    dbg!("hh");
-                    // dbg!("hh",lines.last().unwrap());
-                    // dbg!("hh",lines[lines.len()-2]);
-                    dbg!(q);
-                    dbg!(line.len());
-                    dbg!(&line[0..100]);
-
-                    dbg!( lines.len());
-                    panic!();
-                });
-                let end_of_name_pos2 = line[..end_of_name_pos].find(' ').unwrap_or(end_of_name_pos);
-                let contig_len = line[end_of_name_pos + 1..line.len() - 1].chars().filter(|&x| x != '\n').count();
-                // returns tuple of (contig name string slice, actual contig string slice ) for each line
-            
-                 (
-                        line[..end_of_name_pos2].to_string(),
-                        &line[end_of_name_pos + 1..line.len() - 1])
-                // (
-                //     line[..end_of_name_pos2].to_string(),
-                //     &line[end_of_name_pos + 1..line.len() - 1])
-            })
-            .unzip_into_vecs(&mut contig_names, &mut contigs);
-            //dbg!("here5");
+        // maps  bin -> vector of (contig name, contig) pairs in bin
+        let mut h: HashMap<usize, Vec<(&str,FastaRecord)>> = HashMap::new();
+
+        for (ctg_nm, &bin_n) in contig_name.iter().zip(bin_number.iter()) {
+            let ctg = contig_name_map.get(ctg_nm.as_bytes())
+                                     .ok_or_else(|| PyValueError::new_err(format!("contig {:?} is not in {}", ctg_nm, src_contig_file)))?;
+            h.entry(bin_n).or_insert_with(Vec::new).push((ctg_nm, *ctg));
+        }
+        let write_error = |path: &Path, e: std::io::Error| PyIOError::new_err(format!("failed to write {}: {}", path.display(), e));
+        fs::create_dir(outfolder).map_err(|e| write_error(Path::new(outfolder), e))?;
+        let mut bases_binned = 0;
+        for (bin_num, contents_vec) in h.drain() {
+            let bases = contents_vec.iter()
+                        .map(|(_, ctg)| ctg.bases().count())
+                        .sum::<usize>();
+            if bases < 200000 {
+                continue;
+            }
+            let dest = Path::join(Path::new(outfolder), bin_num.to_string());
+            let write_bin = || -> std::io::Result<()> {
+                let mut file = std::io::BufWriter::new(File::create(&dest)?);
+                for (name,ctg) in contents_vec.iter() {
+                    file.write_all(b">")?;
+                    file.write_all(name.as_bytes())?;
+                    file.write_all(b"\n")?;
+                    // keeps the line wrapping of the source, but always with \n line breaks
+                    for line in ctg.lines() {
+                        file.write_all(line)?;
+                        file.write_all(b"\n")?;
+                    }
+                }
+                file.write_all(b"\n")?;
+                file.flush()
+            };
+            write_bin().map_err(|e| write_error(&dest, e))?;
+            bases_binned += bases;
+        }
+        Ok(bases_binned)
 
 
 
-        let contig_lens = contigs
-                            .par_iter()
-                            .map(|&x| x.chars().filter(|&x| x != '\n').count())
-                            .collect::<Vec<usize>>();
-                            //dbg!("here7");
+    }
+    Ok(())
+}
 
-        let contig_names = contig_names
-            .par_drain(..)
-            .enumerate()
-            .filter(|(a,b)| contig_lens[*a] >= mini_size  )
-            .map(|(a,ctg)|ctg)
-            .collect::<Vec<_>>();
-            //dbg!("here6");
-                // count 1..5 (and l4n1) mers.
-        let pre_tens = contigs
-                .par_iter()
-                .enumerate()
-                .filter(|(a,b)| contig_lens[*a] >= mini_size  )
-                .map(|(a,&ctg)| contig_2_nmer_distrs(ctg))
+// use std::fs::File;
+// use std::io::{BufRead, BufReader};
+
+struct FastaIterator {
+    reader: Box<dyn BufRead + Send>,
+    // the header line of the next fasta record (see read_fasta_record)
+    next_header: Vec<u8>,
+    current_sequence: usize,
+    count: usize,
+    min_size: usize,
+    // fastq input is read a 4 line record at a time instead (see read_fastq_record)
+    fastq: bool,
+    min_quality: Option<u8>,
+}
+
+impl FastaIterator {
+    fn new(file_path: &str, min_size: usize, min_quality: Option<u8>) -> std::io::Result<Self> {
+        let mut reader = open_contig_file(file_path)?;
+        let fastq = records_are_fastq(&mut reader)?;
+
+        let mut counter = open_contig_file(file_path)?;
+        records_are_fastq(&mut counter)?;
+        let mut iter = FastaIterator {
+            reader: counter,
+            next_header: Vec::new(),
+            current_sequence: 0,
+            count: 0,
+            min_size,
+            fastq,
+            min_quality: None,
+        };
+        let mut count = 0;
+        while let Some(seq) = iter.next_sequence()? {
+            if seq.len() >= min_size {
+                count += 1;
+            }
+        }
+
+        Ok(FastaIterator {
+            reader,
+            next_header: Vec::new(),
+            current_sequence: 0,
+            count,
+            min_size,
+            fastq,
+            min_quality,
+        })
+    }
+
+    // the next read or contig, whatever its length
+    fn next_sequence(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        if self.fastq {
+            Ok(read_fastq_record(&mut self.reader, self.min_quality)?.map(|(_, read)| read))
+        } else {
+            Ok(read_fasta_record(&mut self.reader, &mut self.next_header)?.map(|record| FastaRecord::parse(&record).sequence()))
+        }
+    }
+
+    fn skip_to(&mut self, n: usize) -> std::io::Result<()> {
+        while self.current_sequence < n {
+            match self.next_sequence()? {
+                Some(seq) if seq.len() >= self.min_size => self.current_sequence += 1,
+                Some(_) => {}
+                None => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for FastaIterator {
+    // a read error ends the iteration after it is passed on
+    type Item = std::io::Result<Vec<i32>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let seq = match self.next_sequence() {
+                Ok(seq) => seq?,
+                Err(e) => return Some(Err(e)),
+            };
+            if seq.len() >= self.min_size {
+                self.current_sequence += 1;
+                return Some(Ok(tokenize_sequence(&seq)));
+            }
+        }
+    }
+}
+
+fn tokenize_sequence(sequence: &[u8]) -> Vec<i32> {
+    // Convert the DNA sequence to labeled k-mers
+    let mut result = Vec::new();
+    for chunk in sequence.windows(5) {
+        result.push(kmer_to_16_bit_ray::<5>(chunk).unwrap_or_else(|| 1024) as i32);
+    }
+    let window_size = 5;
+    for (idx, i) in (window_size..=sequence.len()).rev().enumerate() {
+        let window = &sequence[i - window_size..i];
+        result.push(kmer_to_16_bit_ray_revcomp::<5>(window).unwrap_or_else( || 1024) as i32);
+      }
+
+    result
+}
+
+use numpy::{ PyArray2};
+use pyo3::prelude::*;
+use numpy::npyffi::types::NPY_ORDER;
+#[pyclass]
+struct PyFastaIterator {
+    inner: FastaIterator,
+}
+
+#[pymethods]
+impl PyFastaIterator {
+    /// Also reads fastq (starting with '@'); min_quality = q tokenizes bases with a Phred quality below q like an N.
+    #[new]
+    #[args(min_quality = "None")]
+    fn new(file_path: &str, min_size: usize, min_quality: Option<u8>) -> PyResult<Self> {
+        Ok(PyFastaIterator {
+            inner: FastaIterator::new(file_path, min_size, min_quality).map_err(|e| contig_file_error(file_path, e))?,
+        })
+    }
+
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<Option<Py<PyArray2<i32>>>> {
+        slf.inner.next().transpose().map_err(|e| PyIOError::new_err(e.to_string()))?.map(|tokens| {
+            let gil = Python::acquire_gil();
+            let py = gil.python();
+            let len = tokens.len();
+            // dbg!(&tokens);
+            let arr = PyArray1::from_vec(py, tokens).reshape_with_order((2, len / 2), NPY_ORDER::NPY_CORDER)?;
+            Ok(arr.to_owned())
+        }).transpose()
+    }
+    fn skip_to(&mut self, n: usize) -> PyResult<()> {
+        self.inner.skip_to(n).map_err(|e| PyIOError::new_err(e.to_string()))
+    }
+    fn size(&self) -> usize {
+        return self.inner.count;
+    }
+
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (name, sequence) of every record, from fasta_records and from the streaming reader (which has to agree with it)
+    fn fasta_names_and_sequences(fasta: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let records = fasta_records(fasta).iter().map(|record| (record.name.to_vec(), record.sequence())).collect::<Vec<_>>();
+        let mut reader = std::io::BufReader::with_capacity(5, fasta);
+        let mut next_header = Vec::new();
+        let streamed = std::iter::from_fn(|| read_fasta_record(&mut reader, &mut next_header).unwrap())
+                .map(|record| {
+                    let record = FastaRecord::parse(&record);
+                    (record.name.to_vec(), record.sequence())
+                })
                 .collect::<Vec<_>>();
-                //dbg!("here6");
+        assert_eq!(records, streamed, "{:?}", String::from_utf8_lossy(fasta));
+        records
+    }
+
+    #[test]
+    fn fasta_parser() {
+        let clean = b">a desc\nACGT\nAC\n>b\nGGG\n>c\n\n>d\tx>y\nTT\n";
+        let expected = [(&b"a"[..], &b"ACGTAC"[..]), (b"b", b"GGG"), (b"c", b""), (b"d", b"TT")]
+                .iter()
+                .map(|&(name, seq)| (name.to_vec(), seq.to_vec()))
+                .collect::<Vec<_>>();
+        assert_eq!(fasta_names_and_sequences(clean), expected);
+        // CRLF line breaks
+        assert_eq!(fasta_names_and_sequences(b">a desc\r\nACGT\r\nAC\r\n>b\r\nGGG\r\n>c\r\n>d\tx>y\r\nTT\r\n"), expected);
+        // blank lines, inside records and between them
+        assert_eq!(fasta_names_and_sequences(b">a desc\n\nACGT\n\nAC\n\n>b\nGGG\n\n\n>c\n\n>d\tx>y\nTT\n\n"), expected);
+        // text before the first '>'
+        assert_eq!(fasta_names_and_sequences(b"\n\r\nsome notes\n>a desc\nACGT\nAC\n>b\nGGG\n>c\n>d\tx>y\nTT\n"), expected);
+        // no newline at the end, after a sequence line or after a header only record
+        assert_eq!(fasta_names_and_sequences(b">a desc\nACGT\nAC\n>b\nGGG\n>c\n>d\tx>y\nTT"), expected);
+        assert_eq!(fasta_names_and_sequences(b">a desc\nACGT\nAC\n>b\nGGG\n>c"), expected[..3]);
+        // header only records, a '>' inside a header
+        assert_eq!(fasta_names_and_sequences(b">\n>e\n>f g>h\n>i>j\nA\n"),
+                   vec![(b"".to_vec(), b"".to_vec()), (b"e".to_vec(), b"".to_vec()), (b"f".to_vec(), b"".to_vec()), (b"i>j".to_vec(), b"A".to_vec())]);
+        assert!(fasta_names_and_sequences(b"").is_empty());
+        assert!(fasta_names_and_sequences(b"\n\nno records\n").is_empty());
+
+        let records = fasta_records(b">a\r\nACGT\r\n\r\nAC");
+        assert_eq!(records[0].lines().collect::<Vec<_>>(), vec![&b"ACGT"[..], b"AC"]);
+        assert_eq!(parse_contigs(clean, 1).unwrap(), vec![("a".to_string(), b"ACGTAC".to_vec()), ("b".to_string(), b"GGG".to_vec()), ("d".to_string(), b"TT".to_vec())]);
+        // a byte order mark doesn't hide the first record
+        assert_eq!(parse_contigs(&[UTF8_BOM, clean].concat(), 1).unwrap(), parse_contigs(clean, 1).unwrap());
+    }
+
+    #[test]
+    fn long_fasta() {
+        // long enough that the '>' search in fasta_records spans chunks
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut fasta = Vec::new();
+        let mut expected = Vec::new();
+        for i in 0..3000 {
+            let len = rng.gen_range(0..1500);
+            let seq = (0..len).map(|_| b"ACGT"[rng.gen_range(0..4)]).collect::<Vec<u8>>();
+            fasta.extend_from_slice(format!(">s{}\n", i).as_bytes());
+            for line in seq.chunks(60) {
+                fasta.extend_from_slice(line);
+                fasta.extend_from_slice(if i % 2 == 0 { b"\n" } else { b"\r\n" });
+            }
+            expected.push((format!("s{}", i), seq));
+        }
+        assert!(fasta.len() > 2 << 20);
+        assert_eq!(parse_contigs(&fasta, 0).unwrap(), expected);
+
+        let path = std::env::temp_dir().join(format!("kmer_counter_long_fasta_{}.fa", std::process::id()));
+        fs::write(&path, &fasta).unwrap();
+        let mut iter = FastaIterator::new(path.to_str().unwrap(), 100, None).unwrap();
+        fs::remove_file(&path).unwrap();
+        let long = expected.iter().filter(|(_, seq)| seq.len() >= 100).collect::<Vec<_>>();
+        assert_eq!(iter.count, long.len());
+        assert_eq!(iter.next().unwrap().unwrap(), tokenize_sequence(&long[0].1));
+        iter.skip_to(10).unwrap();
+        assert_eq!(iter.next().unwrap().unwrap(), tokenize_sequence(&long[10].1));
+    }
+
+    #[test]
+    fn single_code_iupac_bases_are_exact() {
+        let with_ambiguous = |max_ambiguous| CountOptions { max_ambiguous: Some(max_ambiguous), skip_masked: false };
+        let contig = b"ACGTTGCAACGGTCATGCAAGTCCGATGCATGCTAGCA";
+        // R and Y are one RY code each, so they count as that code without using up max_ambiguous
+        let mut ry_coded = contig.to_vec();
+        ry_coded[3] = b'Y';
+        ry_coded[20] = b'R';
+        let exact = contig_2_frac_rymer_counts_bytes(contig, CountOptions::default());
+        let coded = contig_2_frac_rymer_counts_bytes(&ry_coded, with_ambiguous(0));
+        assert_eq!((coded.0, coded.4, coded.5), (exact.0, exact.4, exact.5));
+        // without max_ambiguous they are still skipped like any other base the exact counter doesn't know
+        assert!(contig_2_frac_rymer_counts_bytes(&ry_coded, CountOptions::default()).5 > 0);
+        // an N next to them still counts
+        ry_coded[10] = b'N';
+        assert_eq!(contig_2_frac_rymer_counts_bytes(&ry_coded, with_ambiguous(1)).5, 0);
+        assert!(contig_2_frac_rymer_counts_bytes(&ry_coded, with_ambiguous(0)).5 > 0);
+
+        // the same for S and W with SW, K and M with KM
+        for (alphabet, codes) in [(BinaryAlphabet::SW, [b'W', b'S']), (BinaryAlphabet::KM, [b'M', b'K'])] {
+            // for the A at 0 and the G at 5
+            let mut coded = contig.to_vec();
+            coded[0] = codes[0];
+            coded[5] = codes[1];
+            assert_eq!(binary_counts(&coded, alphabet, &[8, 6], with_ambiguous(0)), binary_counts(contig, alphabet, &[8, 6], CountOptions::default()));
+        }
+        // but for ACGT, R is ambiguous
+        let mut nucleotide_coded = contig.to_vec();
+        nucleotide_coded[20] = b'R';
+        assert!(contig_2_frac_nmer_counts_bytes(&nucleotide_coded, with_ambiguous(0)).5 > 0);
+        assert_eq!(contig_2_frac_nmer_counts_bytes(&nucleotide_coded, with_ambiguous(1)).5, 0);
+    }
+
+    #[test]
+    fn binary_ry_matches_feature_vector() {
+        let mut rng = StdRng::seed_from_u64(5);
+        for len in [12, 200, 5000, 100_000] {
+            let contig = random_contig(&mut rng, len);
+            let (blocks, invalid_count) = binary_distrs(&contig, BinaryAlphabet::RY, &DEFAULT_BINARY_KS, &binary_norms(5), CountOptions::default());
+            let rymers = find_rymers_bytes(&contig, &DEFAULT_NORMS[5..], CountOptions::default());
+            let expected = [&rymers.0[..], &rymers.1[..], &rymers.2[..], &rymers.3[..], &rymers.4[..]];
+            for (block, expected) in blocks.iter().zip(expected) {
+                assert_eq!(block.iter().map(|x| x.to_bits()).collect::<Vec<_>>(), expected.iter().map(|x| x.to_bits()).collect::<Vec<_>>());
+            }
+            assert_eq!(invalid_count, rymers.5);
+        }
+    }
+
+    // 2 bit codes (as kmer_to_16_bit_ray) of every ACGT only k base window on both strands
+    fn window_codes(contig: &[u8], k: usize) -> Vec<u64> {
+        let reverse_compliment = contig.iter().rev().map(|&b| match b { b'A' => b'T', b'T' => b'A', b'C' => b'G', b'G' => b'C', x => x }).collect::<Vec<u8>>();
+        [contig, &reverse_compliment[..]].iter()
+            .flat_map(|strand| strand.windows(k))
+            .filter_map(|w| w.iter().try_fold(0u64, |code, &b| Some(code << 2 | base2number(b)? as u64)))
+            .collect()
+    }
 
-        let pretens_ry= contigs
-                        .par_iter()
-                         .enumerate()
-                      .filter(|(a,b)| contig_lens[*a] >= mini_size )
-                        .map(|(a,&ctg)| find_rymers(ctg))
-                     .collect::<Vec<_>>();
+    fn column(kmer: &[u8]) -> usize {
+        let code = kmer.iter().fold(0u64, |code, &b| code << 2 | base2number(b).unwrap() as u64);
+        canonical_table(kmer.len())[code as usize] as usize
+    }
 
-        let pre_5mers = pre_tens
-                                .par_iter()
-                                .flat_map_iter(|i| i.0)
-                                .collect::<Vec<_>>();
-        
-        let pre_l4n1mers = pre_tens
-                                .par_iter()
-                                .flat_map_iter(|i| i.1)
-                                .collect::<Vec<_>>();
+    #[test]
+    fn markov_ratios_by_hand() {
+        // AACGTT is its own reverse compliment, so both strands have AA, AC, CG, GT and TT once each: f = 0.2 for each,
+        // and f(A) = f(T) = 1/3, f(C) = f(G) = 1/6
+        let ratios = markov_ratios(b"AACGTT".iter().copied(), 2, 0);
+        let mut expected = vec![0.0; 10];
+        // AA: 0.2 / (1/3 * 1/3), AC: 0.2 / (1/3 * 1/6), CG: 0.2 / (1/6 * 1/6); TT and GT are the same columns as AA and AC
+        expected[column(b"AA")] = 1.8;
+        expected[column(b"AC")] = 3.6;
+        expected[column(b"CG")] = 7.2;
+        for (x, y) in ratios.iter().zip(&expected) {
+            assert!((x - y).abs() < 1e-5, "{:?} {:?}", ratios, expected);
+        }
 
-            
-        let pre_4mers = pre_tens
-                                .par_iter()
-                                .flat_map_iter(|i| i.2)
-                                .collect::<Vec<_>>();
-        
-        let pre_3mers = pre_tens
-                                .par_iter()
-                                .flat_map_iter(|i| i.3)
-                                .collect::<Vec<_>>();
-        
-        let pre_2mers = pre_tens
-                                .par_iter()
-                                .flat_map_iter(|i| i.4)
-                                .collect::<Vec<_>>();
-            
-        let pre_1mers = pre_tens
-                                .par_iter()
-                                .flat_map_iter(|i| i.5)
-                                .collect::<Vec<_>>();
-        let pre_10mers = pretens_ry
-                                .par_iter()
-                                .flat_map_iter(|i| i.0)
-                                .collect::<Vec<_>>();
+        // AAC, ACG, CGT and GTT once on each strand, f = 0.25; P(AAC) = f(AA) f(AC) / f(A) = 0.12, P(ACG) = f(AC) f(CG) / f(C) = 0.24
+        let ratios = markov_ratios(b"AACGTT".iter().copied(), 3, 1);
+        let mut expected = vec![0.0; 32];
+        expected[column(b"AAC")] = 2.0833333;
+        expected[column(b"ACG")] = 1.0416667;
+        for (x, y) in ratios.iter().zip(&expected) {
+            assert!((x - y).abs() < 1e-5, "{:?} {:?}", ratios, expected);
+        }
+    }
 
-        let pre_9mers = pretens_ry
-                                .par_iter()
-                                .flat_map_iter(|i| i.1)
-                                .collect::<Vec<_>>();
+    #[test]
+    fn markov_ratios_match_naive() {
+        let mut rng = StdRng::seed_from_u64(13);
+        let contig = random_contig(&mut rng, 3000).to_ascii_uppercase();
+        let reverse_compliment = contig.iter().rev().map(|&b| match b { b'A' => b'T', b'T' => b'A', b'C' => b'G', b'G' => b'C', x => x }).collect::<Vec<u8>>();
+        // the ACGT only windows of a length on both strands, as strings
+        let windows = |m: usize| {
+            [&contig[..], &reverse_compliment[..]].iter()
+                .flat_map(|strand| strand.windows(m))
+                .filter(|w| w.iter().all(|b| b"ACGT".contains(b)))
+                .collect::<Vec<&[u8]>>()
+        };
+        for (k, order) in [(4, 1), (10, 0), (10, 8), (11, 3)] {
+            // the counts of the words of each length, by length
+            let counts = [k, order + 1, order].into_iter()
+                                              .filter(|&m| m > 0)
+                                              .map(|m| {
+                                                  let mut counts = HashMap::new();
+                                                  for w in windows(m) {
+                                                      *counts.entry(w.to_vec()).or_insert(0u32) += 1;
+                                                  }
+                                                  (m, counts)
+                                              })
+                                              .collect::<HashMap<usize, HashMap<Vec<u8>, u32>>>();
+            let sparse = noncanonical_counts(contig.iter().copied(), &[k], true);
+            assert_eq!(sparse[0].seen().collect::<HashMap<_, _>>(), window_codes(&contig, k).into_iter().fold(HashMap::new(), |mut counts, code| {
+                *counts.entry(code).or_insert(0u32) += 1;
+                counts
+            }));
+
+            // the frequency of a word among the words of its length, 1 for the empty word
+            let freq = |w: &[u8]| match counts.get(&w.len()) {
+                Some(counts) => counts.get(w).copied().unwrap_or(0) as f64 / counts.values().sum::<u32>() as f64,
+                None => 1.0,
+            };
+            let mut expected = vec![0.0f32; num_canonical_kmers(k)];
+            for w in counts[&k].keys() {
+                let mut e = freq(&w[..order + 1]);
+                for i in 1..k - order {
+                    e *= freq(&w[i..i + order + 1]);
+                    if order > 0 {
+                        e /= freq(&w[i..i + order]);
+                    }
+                }
+                expected[column(w)] = (freq(w) / e) as f32;
+            }
+            let ratios = markov_ratios(contig.iter().copied(), k, order);
+            for (x, y) in ratios.iter().zip(&expected) {
+                assert!((x - y).abs() <= 1e-5 * y, "k {} order {}: {} {}", k, order, x, y);
+            }
+        }
+    }
 
-        let pre_8mers = pretens_ry
-                                .par_iter()
-                                .flat_map_iter(|i| i.2)
-                                .collect::<Vec<_>>();
-        let pre_7mers = pretens_ry
-                                .par_iter()
-                                .flat_map_iter(|i| i.3)
-                                .collect::<Vec<_>>();
-        let pre_6mers = pretens_ry
-                                .par_iter()
-                                .flat_map_iter(|i| i.4)
-                                .collect::<Vec<_>>();
+    #[test]
+    fn packed_seq_round_trip() {
+        let mut rng = StdRng::seed_from_u64(17);
+        for len in [1, 2, 3, 5, 7, 401, 1003] {
+            // runs of ACGT, IUPAC codes and other bytes, some of them lowercase, so runs straddle the slices
+            let mut seq = Vec::new();
+            while seq.len() < len {
+                let run = rng.gen_range(1..12);
+                let alphabet: &[u8] = match rng.gen_range(0..4) {
+                    0 | 1 => b"ACGT",
+                    2 => b"N",
+                    _ => b"RYSWKMBDHVNU-*",
+                };
+                let lower = rng.gen_bool(0.3);
+                let b = alphabet[rng.gen_range(0..alphabet.len())];
+                seq.extend((0..run).map(|_| {
+                    let b = if alphabet.len() == 4 { alphabet[rng.gen_range(0..4)] } else { b };
+                    if lower { b.to_ascii_lowercase() } else { b }
+                }));
+            }
+            seq.truncate(len);
+            assert!(len % 4 != 0);
+
+            let packed = PackedSeq::new(&seq);
+            assert_eq!(packed.decode(0, len), seq);
+            for _ in 0..200 {
+                let start = rng.gen_range(0..=len);
+                let slice_len = rng.gen_range(0..=len - start);
+                assert_eq!(packed.decode(start, slice_len), seq[start..start + slice_len], "len {} slice {}+{}", len, start, slice_len);
+            }
+            // every slice of the short ones
+            if len < 10 {
+                for start in 0..=len {
+                    for end in start..=len {
+                        assert_eq!(packed.decode(start, end - start), seq[start..end]);
+                    }
+                }
+            }
+        }
+    }
 
-        let contig_lens = contig_lens
-                                .par_iter()
-                                .filter(|x| *x >= &0)
-                                .map(|x| *x)
-                                .collect::<Vec<usize>>();
-                                //dbg!("here7");
-        (
-            contig_lens,
-            pre_5mers.into_pyarray(py),
-            pre_4mers.into_pyarray(py),
-            pre_3mers.into_pyarray(py),
-            pre_2mers.into_pyarray(py),
-            pre_1mers.into_pyarray(py),
-            pre_10mers.into_pyarray(py),
-            pre_9mers.into_pyarray(py),
-            pre_8mers.into_pyarray(py),
-            pre_7mers.into_pyarray(py),
-            pre_6mers.into_pyarray(py),
-            contig_names
-        )
+    // Frozen copies of the counters before the rolling window (RollingKmer) replaced them: every window re-encoded with
+    // kmer_to_16_bit_ray / kmer_to_16_bit_ray_ry for every k. Don't update these, they are what the counts must match.
+    fn old_kmer_code(mer: &[u8], encode: fn(u8) -> Option<u8>, bits: usize) -> Option<u32> {
+        mer.iter().try_fold(0u32, |code, &c| Some(code << bits | encode(c)? as u32))
     }
 
-    #[pyfn(m)]
-    #[pyo3(name = "acumen")]
-    pub fn acumen<'py>(py: Python<'py>, filepath: String,  names: Vec<String>, src: Vec<usize>, dst: Vec<usize>, weights: Vec<f32>) -> Vec<isize>{
-        let markers = find_markers(&filepath, &names);
-        let (root_idx, tree_vec) = build_dendrogram(weights, src, dst, markers); 
-        let root_idx = tree_vec.len() - 1;
-        let (contig_idxs, mut cluster_start) = traverse_tree(&tree_vec, root_idx as u32);
-        cluster_start.push(contig_idxs.len());
-        let mut result = vec![-1;names.len()];
+    fn old_base2number(c: u8) -> Option<u8> {
+        match c {
+            b'A' | b'a' => Some(0),
+            b'T' | b't' => Some(1),
+            b'C' | b'c' => Some(2),
+            b'G' | b'g' => Some(3),
+            _ => None,
+        }
+    }
 
+    fn old_base2number_ry(c: u8) -> Option<u8> {
+        match c {
+            b'A' | b'a' | b'G' | b'g' => Some(0),
+            b'T' | b't' | b'C' | b'c' => Some(1),
+            _ => None,
+        }
+    }
 
-        for (cluster_num, window) in cluster_start.windows(2)
-                                .enumerate() {
-            let start = window[0];
-            let end = window[1];
-            for contig_idx  in contig_idxs[start..end].iter() {
-                result[*contig_idx as usize] = cluster_num as isize;
+    // the old str counters dropped line breaks after the first window
+    fn old_nmer_counts(contig: &[u8]) -> NmerCounts {
+        let mut counts: NmerCounts = ([0; 512], [0; 136], [0; 32], [0; 10], [0; 2], 0);
+        let mut fmer = [0, contig[0], contig[1], contig[2], contig[3]];
+        for &b in contig[4..].iter().filter(|&&b| b != b'\n') {
+            fmer.copy_within(1..5, 0);
+            fmer[4] = b;
+            match old_kmer_code(&fmer, old_base2number, 2) {
+                Some(f) => counts.0[FIVEMERTABLE[f as usize] as usize] += 1,
+                None => counts.5 += 1,
+            }
+            if let Some(f) = old_kmer_code(&fmer[..4], old_base2number, 2) {
+                counts.1[FOURMERTABLE[f as usize] as usize] += 1;
+            }
+            if let Some(f) = old_kmer_code(&fmer[..3], old_base2number, 2) {
+                counts.2[THREEMERTABLE[f as usize] as usize] += 1;
+            }
+            if let Some(f) = old_kmer_code(&fmer[..2], old_base2number, 2) {
+                counts.3[TWOMERTABLE[f as usize] as usize] += 1;
+            }
+            if let Some(f) = old_kmer_code(&fmer[..1], old_base2number, 2) {
+                counts.4[ONEMERTABLE[f as usize] as usize] += 1;
             }
         }
-        result
+        // the shorter kmers of the last window that no window starts with
+        if let Some(f) = old_kmer_code(&fmer[1..5], old_base2number, 2) {
+            counts.1[FOURMERTABLE[f as usize] as usize] += 1;
+        }
+        for i in 1..3 {
+            if let Some(f) = old_kmer_code(&fmer[i..3 + i], old_base2number, 2) {
+                counts.2[THREEMERTABLE[f as usize] as usize] += 1;
+            }
+        }
+        for i in 1..4 {
+            if let Some(f) = old_kmer_code(&fmer[i..2 + i], old_base2number, 2) {
+                counts.3[TWOMERTABLE[f as usize] as usize] += 1;
+            }
+        }
+        for i in 1..5 {
+            if let Some(f) = old_kmer_code(&fmer[i..1 + i], old_base2number, 2) {
+                counts.4[ONEMERTABLE[f as usize] as usize] += 1;
+            }
+        }
+        counts
+    }
 
+    fn old_rymer_counts(contig: &[u8]) -> RymerCounts {
+        let mut counts: RymerCounts = ([0; 528], [0; 256], [0; 136], [0; 64], [0; 36], 0);
+        let mut fmer = [0u8; 10];
+        fmer[1..].copy_from_slice(&contig[..9]);
+        for &b in contig[9..].iter().filter(|&&b| b != b'\n') {
+            fmer.copy_within(1..10, 0);
+            fmer[9] = b;
+            match old_kmer_code(&fmer, old_base2number_ry, 1) {
+                Some(f) => counts.0[RY10TBL[f as usize] as usize] += 1,
+                None => counts.5 += 1,
+            }
+            if let Some(f) = old_kmer_code(&fmer[..9], old_base2number_ry, 1) {
+                counts.1[RY9TBL[f as usize] as usize] += 1;
+            }
+            if let Some(f) = old_kmer_code(&fmer[..8], old_base2number_ry, 1) {
+                counts.2[RY8TBL[f as usize] as usize] += 1;
+            }
+            if let Some(f) = old_kmer_code(&fmer[..7], old_base2number_ry, 1) {
+                counts.3[RY7TBL[f as usize] as usize] += 1;
+            }
+            if let Some(f) = old_kmer_code(&fmer[..6], old_base2number_ry, 1) {
+                counts.4[RY6TBL[f as usize] as usize] += 1;
+            }
+        }
+        counts
     }
 
-    #[pyfn(m)]
-    #[pyo3(name = "write_fasta_bins")]
-    pub fn write_fasta_bins<'py>(py: Python<'py>, contig_name: Vec<String>, bin_number: Vec<usize> ,src_contig_file: &str, outfolder: &str) -> usize {
+    #[test]
+    fn counts_match_old_counters() {
+        let mut rng = StdRng::seed_from_u64(19);
+        for len in [10, 11, 17, 100, 5000] {
+            for _ in 0..5 {
+                // random_contig has N and lowercase, line breaks go after the first window like the old str counters expect
+                let mut contig = random_contig(&mut rng, len);
+                for _ in 0..len / 50 {
+                    let pos = rng.gen_range(10..=contig.len());
+                    contig.insert(pos, b'\n');
+                }
+                let bases = || contig.iter().copied().filter(|&b| b != b'\n');
+                assert_eq!(nmer_counts(bases()), old_nmer_counts(&contig), "len {}", len);
+                assert_eq!(rymer_counts(bases()), old_rymer_counts(&contig), "len {}", len);
+            }
+        }
+    }
 
-        let file_info = fs::metadata(src_contig_file).unwrap();
-        let file_size = file_info.len();
+    fn assert_close(result: &[f32], expected: &[f64]) {
+        assert_eq!(result.len(), expected.len());
+        for (x, y) in result.iter().zip(expected) {
+            assert!((*x as f64 - y).abs() < 1e-6 || (x.is_nan() && y.is_nan()), "{:?} {:?}", result, expected);
+        }
+    }
 
-        let mut file = File::open(src_contig_file).unwrap();
+    #[test]
+    fn clr_and_zscore_by_hand() {
+        let mut result = [0.0f32; 3];
+        // ln(1.5), ln(0.5), ln(3.5) minus their mean ln(2.625) / 3 = 0.32169...
+        normalize_into(&[1.0, 0.0, 3.0], &Normalization::Clr(0.5), &mut result);
+        let mean = (1.5f64.ln() + 0.5f64.ln() + 3.5f64.ln()) / 3.0;
+        assert_close(&result, &[1.5f64.ln() - mean, 0.5f64.ln() - mean, 3.5f64.ln() - mean]);
+        assert_close(&result, &[0.08377148, -1.01484080, 0.93106932]);
+
+        // count blocks are L1 normed first: [1, 3] -> [0.25, 0.75]
+        let mut result = [0.0f32; 2];
+        let zscore = |base: Normalization, mean: Vec<f32>, std: Vec<f32>| Normalization::ZScore(Box::new(base), mean, std);
+        normalize_into(&[1.0, 3.0], &zscore(DEFAULT_NORMS[0].clone(), vec![0.5, 0.5], vec![0.25, 0.5]), &mut result);
+        assert_close(&result, &[-1.0, 0.5]);
+        // RY quotient blocks L2: [3, 4] -> [0.6, 0.8]; a std of 0 gives 0
+        normalize_into(&[3.0, 4.0], &zscore(DEFAULT_NORMS[5].clone(), vec![0.6, 0.4], vec![0.1, 0.0]), &mut result);
+        assert_close(&result, &[0.0, 0.0]);
+        normalize_into(&[3.0, 4.0], &zscore(DEFAULT_NORMS[6].clone(), vec![0.5, 0.4], vec![0.1, 0.2]), &mut result);
+        assert_close(&result, &[1.0, 2.0]);
+    }
 
-        //read using multiple file handles for faster spead
-        let mut read_buffer: Vec<u8> = Vec::new();
-        par_read(&mut file, &mut read_buffer, file_size);
+    fn fastq_reads(fastq: &[u8], min_quality: Option<u8>) -> std::io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut reader = std::io::BufReader::with_capacity(7, fastq);
+        std::iter::from_fn(|| read_fastq_record(&mut reader, min_quality).transpose()).collect()
+    }
 
-        let mut decom_buffer: Vec<u8> = Vec::new();
+    #[test]
+    fn fastq_records() {
+        let fastq = b"@r1 extra\nACGTAC\n+\nIIII#I\n\n@r2\tx\nAC\n+\n##\n\n\n@r3\r\nGGGGTT\r\n+r3\r\n!!IIII";
+        let reads = fastq_reads(fastq, Some(20)).unwrap();
+        assert_eq!(reads, vec![(b"r1".to_vec(), b"ACGTNC".to_vec()), (b"r2".to_vec(), b"NN".to_vec()), (b"r3".to_vec(), b"NNGGTT".to_vec())]);
+        // the whole file readers go through the same record reader
+        assert_eq!(parse_fastq(fastq, 3, Some(20)).unwrap(), vec![("r1".to_string(), b"ACGTNC".to_vec()), ("r3".to_string(), b"NNGGTT".to_vec())]);
+        assert_eq!(parse_contigs(fastq, 3).unwrap(), vec![("r1".to_string(), b"ACGTAC".to_vec()), ("r3".to_string(), b"GGGGTT".to_vec())]);
+
+        // a byte order mark or blank lines before the first record don't hide that it's fastq
+        for prefix in [&b"\n\r\n"[..], UTF8_BOM, b"\xef\xbb\xbf\n"] {
+            let text = [prefix, &fastq[..]].concat();
+            assert!(is_fastq(&text));
+            assert_eq!(parse_contigs(&text, 3).unwrap(), vec![("r1".to_string(), b"ACGTAC".to_vec()), ("r3".to_string(), b"GGGGTT".to_vec())]);
+            let mut reader = &text[..];
+            assert!(records_are_fastq(&mut reader).unwrap());
+            assert_eq!(fastq_reads(reader, Some(20)).unwrap(), reads);
+        }
+        assert!(!is_fastq(b"\xef\xbb\xbf>a\nACGT\n"));
+    }
 
-        //covert to string representation for easy tooling (slightly slower, but easy)
-         let string_slice_rep: &str = if src_contig_file.len() >3 && b'z'==src_contig_file.as_bytes()[src_contig_file.len()-1] {    
-            let mut gz = GzDecoder::new(&read_buffer[..]);
-            gz.read_to_end(&mut  decom_buffer).unwrap();
-            std::str::from_utf8(&decom_buffer[..]).unwrap() 
-        } else {
-            std::str::from_utf8(&read_buffer[..]).unwrap() 
-        };
+    fn random_contig(rng: &mut StdRng, len: usize) -> Vec<u8> {
+        (0..len).map(|_| b"ACGTACGTACGTacgtN"[rng.gen_range(0..17)]).collect()
+    }
 
+    fn test_extra_blocks() -> ExtraBlocks {
+        ExtraBlocks { markov: Some((4, 1)), quotients: true, contig_stats: Some(5), masked_fraction: true, ..Default::default() }
+    }
 
-        // split on new fasta name delimitor, skip first trivial
-        let lines = string_slice_rep.par_split('>').filter(|x| x.len() != 0).collect::<Vec<_>>();
+    fn batch_bits(batch: NmerBatch) -> (Vec<usize>, Vec<Vec<u32>>, Vec<String>) {
+        (batch.contig_lens, batch.blocks.iter().map(|block| block.iter().map(|x| x.to_bits()).collect()).collect(), batch.contig_names)
+    }
 
-        let num_contigs = lines.len();
+    fn batches_bits(input: &[u8], batch_size: usize, reader_capacity: usize) -> (Vec<usize>, Vec<Vec<u32>>, Vec<String>) {
+        let mut reader = std::io::BufReader::with_capacity(reader_capacity, input);
+        batch_bits(nmer_distributions(&mut reader, batch_size, Some(20), 30, &DEFAULT_NORMS, CountOptions::default(), &test_extra_blocks()).unwrap())
+    }
 
-  
-        let contig_name_map = lines
-            .par_iter()
-            .map(|line| {
-                let end_of_name_pos = line.find('\n').unwrap_or_else(|| {
-                    dbg!(line);
-                    dbg!(lines.last().unwrap());
-                    dbg!(lines[lines.len()-2]);
-
-                    dbg!(lines.len());
-                    panic!();
-                });
-                let end_of_name_pos2 = line[..end_of_name_pos].find(' ').unwrap_or(end_of_name_pos);
-
-                // returns tuple of (contig name string slice, actual contig string slice ) for each line
-                (
-                    &line[..end_of_name_pos2],
-                    &line[end_of_name_pos + 1..line.len() - 1],
-                )
-            }) // collects tuples into these vecs 
-            .collect::<HashMap<&str, &str>>();
-        
+    #[test]
+    fn batch_size_doesnt_change_output() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut fasta = b"\n>c0 first\r\n".to_vec();
+        let mut fastq = Vec::new();
+        for i in 0..40 {
+            let len = rng.gen_range(0..300);
+            let contig = random_contig(&mut rng, len);
+            if i > 0 {
+                fasta.extend_from_slice(format!(">c{}\tx>y\n", i).as_bytes());
+            }
+            for line in contig.chunks(60) {
+                fasta.extend_from_slice(line);
+                fasta.extend_from_slice(if i % 2 == 0 { b"\r\n" } else { b"\n" });
+            }
+            fastq.extend_from_slice(format!("@r{} x\n", i).as_bytes());
+            fastq.extend_from_slice(&contig);
+            fastq.extend_from_slice(b"\n+\n");
+            fastq.extend((0..contig.len()).map(|_| rng.gen_range(33..75u8)));
+            fastq.extend_from_slice(if i % 3 == 0 { b"\n\n" } else { b"\n" });
+        }
+        for input in [&fasta, &fastq] {
+            let whole = batches_bits(input, usize::MAX, 1 << 20);
+            assert!(whole.0.len() > 20);
+            for (batch_size, reader_capacity) in [(0, 1 << 20), (1, 16), (500, 64), (3000, 1 << 20)] {
+                assert_eq!(batches_bits(input, batch_size, reader_capacity), whole, "batch_size {}", batch_size);
+            }
+            assert_eq!(batches_bits(&[UTF8_BOM, input].concat(), 500, 64), whole);
+        }
+        // without batch_size a fasta in memory is split by fasta_records instead
+        let in_memory = nmer_batch(&fasta_records(&fasta), 30, &DEFAULT_NORMS, CountOptions::default(), &test_extra_blocks());
+        assert_eq!(batch_bits(in_memory), batches_bits(&fasta, usize::MAX, 1 << 20));
+
+        // the contig_stats block is what find_contig_stats computes, the masked fraction what find_masked_counts does
+        let contigs = parse_contigs(&fasta, 30).unwrap();
+        let stats = contigs.iter().flat_map(|(_, ctg)| contig_stats(ctg, 5)).map(f32::to_bits).collect::<Vec<_>>();
+        let masked = contigs.iter()
+                            .map(|(_, ctg)| (masked_block_counts(ctg.iter().copied(), false).2 as f32 / ctg.len() as f32).to_bits())
+                            .collect::<Vec<_>>();
+        let markov = contigs.iter().flat_map(|(_, ctg)| markov_ratios(ctg.iter().copied(), 4, 1)).map(f32::to_bits).collect::<Vec<_>>();
+        let blocks = batches_bits(&fasta, 500, 64).1;
+        assert_eq!(blocks[blocks.len() - 2..], [stats, masked]);
+        assert_eq!(blocks[1], markov);
+    }
 
-        // maps  bin -> vector of (contig name, contig) pairs in bin
-        let mut h: HashMap<usize, Vec<(&str,&str)>> = HashMap::new();
+    fn bgzf_block(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::GzBuilder::new().extra(vec![b'B', b'C', 2, 0, 0, 0]).write(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        let mut block = encoder.finish().unwrap();
+        let bsize = (block.len() - 1) as u16;
+        block[16..18].copy_from_slice(&bsize.to_le_bytes());
+        block
+    }
 
-        for (ctg_nm, &bin_n) in contig_name.iter().zip(bin_number.iter()) {
-            let mut v = h.entry(bin_n).or_insert_with(|| Vec::new());
-            v.push((ctg_nm,*contig_name_map.get(&ctg_nm[..]).unwrap()));
-        }
-        fs::create_dir(outfolder).unwrap();
-        let bases_binned = h.drain()
-               .filter_map(|(bin_num, contents_vec)| {
-                   let bases = contents_vec.iter()
-                               .flat_map(|(a,b)| b.chars())
-                               .filter(|&x| x != '\n')
-                               .count();
-                    if bases >= 200000 {
-                        return Some((bin_num, contents_vec,bases));
-                    }
-                    None
-               })
-               .map(|(bin_num, contents_vec,bases)|{
-                    let dest= Path::join(Path::new(outfolder), bin_num.to_string());
-                    let mut file = File::create(dest).unwrap();
-                    for (name,ctg) in contents_vec.iter() {
-                        file.write_all(&[b'>']);
-                        file.write_all(name.as_bytes());
-                        file.write_all(&[b'\n']);
-                        file.write_all(ctg.as_bytes());
-                        file.write_all(&[b'\n']);
-                    }
-                    file.write_all(&[b'\n']);
-                    bases
-               }).sum();
-        return bases_binned;
+    #[test]
+    fn bgzf_decompression() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let text = (0..300_000).map(|i| if i % 61 == 60 { b'\n' } else { b"ACGT"[rng.gen_range(0..4)] }).collect::<Vec<u8>>();
+        // bgzip ends with an empty block
+        let bgzf = text.chunks(60_000).map(bgzf_block).chain(std::iter::once(bgzf_block(b""))).collect::<Vec<_>>().concat();
+        let blocks = bgzf_blocks(&bgzf).unwrap();
+        assert_eq!(blocks.len(), 6);
+        assert_eq!(decompress(bgzf.clone()).unwrap(), text);
+
+        // streamed in batches of blocks, also when a batch ends inside a block
+        for batch_size in [1, 1000, 70_000, BGZF_BATCH_SIZE] {
+            let mut streamed = Vec::new();
+            BgzfReader::new(std::io::BufReader::with_capacity(100, &bgzf[..]), batch_size).read_to_end(&mut streamed).unwrap();
+            assert_eq!(streamed, text, "batch_size {}", batch_size);
+        }
 
+        // plain multi-member gzip isn't BGZF, its members are found by decompressing from every 1f 8b 08
+        let plain_gz = |data: &[u8]| {
+            // the name holds a 1f 8b 08 that isn't the start of a member
+            let mut encoder = flate2::GzBuilder::new().filename(&[b'x', 0x1f, 0x8b, 8][..]).write(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+        let members = text.chunks(70_000).map(plain_gz).collect::<Vec<_>>();
+        assert!(bgzf_blocks(&members[0]).is_none());
+        assert_eq!(gzip_member(&members.concat()).unwrap(), (members[0].len(), text[..70_000].to_vec()));
+        assert_eq!(decompress(members.concat()).unwrap(), text);
+        assert_eq!(decompress(members[0].clone()).unwrap(), text[..70_000]);
+        let mut bad = members.clone();
+        let len = bad[2].len();
+        bad[2][len - 6] ^= 0xff;
+        assert!(decompress(bad.concat()).is_err());
+        let mut trailing = members.concat();
+        trailing.extend_from_slice(b"\0\0\0");
+        let mut expected = Vec::new();
+        let serial = MultiGzDecoder::new(&trailing[..]).read_to_end(&mut expected).map(|_| expected);
+        assert_eq!(decompress(trailing).ok(), serial.ok());
+
+        // a stream that stops being BGZF is read on serially
+        let mixed = [&bgzf[..bgzf.len() - 28], &members[0]].concat();
+        let mut streamed = Vec::new();
+        BgzfReader::new(&mixed[..], 1000).read_to_end(&mut streamed).unwrap();
+        assert_eq!(streamed, [&text[..], &text[..70_000]].concat());
+
+        // truncated, oversized or corrupt blocks
+        assert!(bgzf_blocks(&bgzf[..bgzf.len() - 3]).is_none());
+        let too_big = bgzf_block(&vec![b'A'; MAX_BGZF_BLOCK_SIZE + 1]);
+        assert!(bgzf_blocks(&too_big).is_none());
+        assert_eq!(decompress(too_big).unwrap().len(), MAX_BGZF_BLOCK_SIZE + 1);
+        let mut bad = bgzf.clone();
+        bad[40] ^= 0xff;
+        assert!(decompress(bad.clone()).is_err());
+        assert!(BgzfReader::new(&bad[..], 1000).read_to_end(&mut Vec::new()).is_err());
+    }
 
+    #[test]
+    fn malformed_fastq() {
+        for fastq in [&b"@r1\nACGT\nIIII\n@r2\nACGT\n+\nIIII\n"[..], b"@r1\nACGT\n+\nIII\n", b"@r1\nACGT\n", b"r1\nACGT\n+\nIIII\n",
+                      b"@r1\nACGT\n+\nIIII\nACGT\n+\nIIII\n"] {
+            let e = fastq_reads(fastq, None).unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+            assert!(parse_fastq(fastq, 0, None).is_err());
+        }
+    }
 
+    fn table(id: usize) -> [u8; 64] {
+        translation_table(GENETIC_CODES.iter().find(|x| x.0 == id).unwrap().1)
     }
-    Ok(())
-}
 
-// use std::fs::File;
-// use std::io::{BufRead, BufReader};
+    fn codon(c: &[u8]) -> usize {
+        kmer_to_16_bit_ray::<3>(c).unwrap() as usize
+    }
 
-struct FastaIterator {
-    reader: BufReader<File>,
-    buffer: Vec<u8>,
-    sequence: Vec<u8>,
-    current_sequence: usize,
-    count: usize,
-    min_size: usize,
-}
+    fn aa(c: u8) -> usize {
+        AMINO_ACIDS.iter().position(|&x| x == c).unwrap()
+    }
 
-impl FastaIterator {
-    fn new(file_path: &str, min_size: usize) -> Self {
-        let file = File::open(file_path).expect("Failed to open FASTA file");
-        let count = BufReader::new(File::open(file_path).expect("Failed to open FASTA file"))
-            .split(b'>')
-            .filter(|result| tokenize_dna_fast(result.as_ref().unwrap(), min_size))
-            .count();
-        
+    #[test]
+    fn translation_tables_follow_tcag_order() {
+        for (id, aas) in GENETIC_CODES {
+            let code = table(id);
+            for (i, &expected) in aas.as_bytes().iter().enumerate() {
+                let c = [b"TCAG"[i / 16], b"TCAG"[(i / 4) % 4], b"TCAG"[i % 4]];
+                assert_eq!(AMINO_ACIDS[code[codon(&c)] as usize], expected, "table {} codon {}", id, String::from_utf8_lossy(&c));
+            }
+        }
+        let (bacterial, mycoplasma) = (table(11), table(4));
+        assert_eq!(bacterial[codon(b"ATG")] as usize, aa(b'M'));
+        assert_eq!(bacterial[codon(b"TTT")] as usize, aa(b'F'));
+        assert_eq!(bacterial[codon(b"GGG")] as usize, aa(b'G'));
+        for stop in [b"TAA", b"TAG", b"TGA"] {
+            assert_eq!(bacterial[codon(stop)], STOP);
+        }
+        assert_eq!(mycoplasma[codon(b"TGA")] as usize, aa(b'W'));
+        assert_eq!(mycoplasma[codon(b"TAA")], STOP);
+    }
 
-        FastaIterator {
-            reader: BufReader::new(file),
-            sequence: Vec::new(),
-            buffer: Vec::new(),
-            current_sequence: 0,
-            count: count,
-            min_size: min_size
+    #[test]
+    fn six_frame_translation_by_hand() {
+        // fwd ATG AAA TGA (M K *), TGA AAT (* N), GAA ATG (E M)
+        // rev TCATTTCAT: TCA TTT CAT (S F H), CAT TTC (H F), ATT TCA (I S)
+        let (codons, aas, dipeptides) = contig_2_codon_distrs_bytes(b"ATGAAATGA", &table(11), None);
+        let mut expected = [0.0f32; 21];
+        for (a, n) in [(b'M', 2), (b'K', 1), (b'*', 2), (b'N', 1), (b'E', 1), (b'S', 2), (b'F', 2), (b'H', 2), (b'I', 1)] {
+            expected[aa(a)] = n as f32 / 14.0;
         }
+        assert_eq!(aas, expected);
+        assert_eq!(codons[codon(b"TGA")], 2.0 / 14.0);
+        assert_eq!(codons[codon(b"TCA")], 2.0 / 14.0);
+        assert_eq!(codons[codon(b"ATT")], 1.0 / 14.0);
+        assert_eq!(codons.iter().filter(|&&x| x > 0.0).count(), 10);
+        // stops break dipeptides: no *N, and K* isn't counted
+        let mut expected = [0.0f32; 400];
+        for pair in [b"MK", b"EM", b"SF", b"FH", b"HF", b"IS"] {
+            expected[aa(pair[0]) * 20 + aa(pair[1])] = 1.0 / 6.0;
+        }
+        assert_eq!(dipeptides, expected);
+
+        // a N only breaks the codons it's in, and a revcomp contig translates the same
+        let (codons, aas, dipeptides) = contig_2_codon_distrs_bytes(b"ATGNAATGA", &table(11), None);
+        assert_eq!(codons.iter().filter(|&&x| x > 0.0).count(), 6);
+        assert_eq!(aas[aa(b'K')], 0.0);
+        assert_eq!(dipeptides[aa(b'M') * 20 + aa(b'K')], 0.0);
+        assert_eq!(contig_2_codon_distrs_bytes(b"TCATTTCAT", &table(11), None), contig_2_codon_distrs_bytes(b"ATGAAATGA", &table(11), None));
+    }
+
+    #[test]
+    fn orfs_split_at_the_table_stops() {
+        // fwd frame 0: ATG TGG TGA AAA CCC, rev frame 0: GGG TTT TCA CCA CAT (G F S P H), the other frames are 4 codons
+        let contig = b"ATGTGGTGAAAACCC";
+        // TGA is a stop in table 11, leaving two stretches of 2 codons, too short
+        let (codons, aas, _) = contig_2_codon_distrs_bytes(contig, &table(11), Some(5));
+        let mut expected = [0.0f32; 21];
+        for a in *b"GFSPH" {
+            expected[aa(a)] = 0.2;
+        }
+        assert_eq!(aas, expected);
+        assert_eq!(codons[codon(b"ATG")], 0.0);
+        // and W in table 4: the whole forward frame is an ORF of 5 codons
+        let (codons, aas, dipeptides) = contig_2_codon_distrs_bytes(contig, &table(4), Some(5));
+        let mut expected = [0.0f32; 21];
+        for (a, n) in [(b'M', 1), (b'W', 2), (b'K', 1), (b'P', 2), (b'G', 1), (b'F', 1), (b'S', 1), (b'H', 1)] {
+            expected[aa(a)] = n as f32 / 10.0;
+        }
+        assert_eq!(aas, expected);
+        assert_eq!(codons[codon(b"TGA")], 0.1);
+        assert_eq!(dipeptides[aa(b'W') * 20 + aa(b'W')], 1.0 / 8.0);
+        // stops themselves are never counted in ORFs, and too short ORFs give all 0
+        assert_eq!(contig_2_codon_distrs_bytes(b"ATGTAAATGAAATAG", &table(11), Some(1)).1[STOP as usize], 0.0);
+        assert_eq!(contig_2_codon_distrs_bytes(contig, &table(4), Some(6)), ([0.0; 64], [0.0; 21], [0.0; 400]));
     }
 
-    fn skip_to(&mut self, n: usize) {
-        let num_skip = n-self.current_sequence;
+    fn revcomp(seq: &[u8]) -> Vec<u8> {
+        seq.iter().rev().map(|&b| match b {
+            b'A' => b'T', b'C' => b'G', b'G' => b'C', b'T' => b'A',
+            b'a' => b't', b'c' => b'g', b'g' => b'c', b't' => b'a',
+            other => other,
+        }).collect()
+    }
 
-        let mut i = 0;
+    // canonical k mers of the contigs as strings, skipping windows with a non ACGT base
+    fn naive_kmer_set(contigs: &[&[u8]], k: usize) -> std::collections::HashSet<Vec<u8>> {
+        contigs.iter()
+               .flat_map(|ctg| ctg.windows(k))
+               .map(|w| w.to_ascii_uppercase())
+               .filter(|w| w.iter().all(|b| b"ACGT".contains(b)))
+               .map(|w| w.clone().min(revcomp(&w)))
+               .collect()
+    }
 
-        while i < num_skip {
-                let bytes_read = self.reader.read_until(b'>', &mut self.buffer).ok();
-    
-                if bytes_read == Some(0) {
-                    // dbg!("a");
-                    if !self.sequence.is_empty() {
-                        // dbg!("b");
-                        let res = tokenize_dna_fast(&self.sequence,self.min_size);
-                        if res == false {
-                            continue;
+    #[test]
+    fn sketches_by_hand() {
+        let mut rng = StdRng::seed_from_u64(12);
+        let k = 21;
+        let a = random_contig(&mut rng, 2000);
+        // b shares its first 1200 bases with a
+        let b = [&a[..1200], &random_contig(&mut rng, 800)[..]].concat();
+
+        // reverse complement and case don't change the sketch
+        let sketch_a = Sketch::from_contigs(std::iter::once(&a), k, 1);
+        assert_eq!(sketch_a.hashes, Sketch::from_contigs(std::iter::once(revcomp(&a)), k, 1).hashes);
+        assert_eq!(sketch_a.hashes, Sketch::from_contigs(std::iter::once(a.to_ascii_uppercase()), k, 1).hashes);
+
+        // with scaled 1 the sketches are exact, so they agree with the k mer sets
+        let sketch_b = Sketch::from_contigs(std::iter::once(&b), k, 1);
+        let (set_a, set_b) = (naive_kmer_set(&[&a], k), naive_kmer_set(&[&b], k));
+        let shared = set_a.intersection(&set_b).count();
+        assert_eq!(sketch_a.hashes.len(), set_a.len());
+        assert_eq!(sketch_b.hashes.len(), set_b.len());
+        assert_eq!(shared, naive_kmer_set(&[&a[..1200]], k).len());
+        assert_eq!(sketch_a.overlap(&sketch_b), (set_a.len(), set_b.len(), shared));
+        assert_eq!(sketch_a.jaccard_unch(&sketch_b), shared as f64 / (set_a.len() + set_b.len() - shared) as f64);
+        assert_eq!(sketch_a.containment_unch(&sketch_b), shared as f64 / set_a.len() as f64);
+        assert_eq!(sketch_b.containment_unch(&sketch_b), 1.0);
+        // a prefix of a is fully contained in a, but not the other way around
+        let prefix = Sketch::from_contigs(std::iter::once(&a[..1200]), k, 1);
+        assert_eq!(prefix.containment_unch(&sketch_a), 1.0);
+        assert!(sketch_a.containment_unch(&prefix) < 0.7);
+
+        // a coarser sketch is the finer one's hashes below its max_hash, and comparing against the finer one downsamples it
+        let coarse_a = Sketch::from_contigs(std::iter::once(&a), k, 10);
+        let coarse_b = Sketch::from_contigs(std::iter::once(&b), k, 10);
+        assert!(!coarse_a.hashes.is_empty());
+        assert_eq!(coarse_a.hashes, sketch_a.hashes.iter().copied().filter(|&h| h <= u64::MAX / 10).collect::<Vec<_>>());
+        assert_eq!(sketch_a.overlap(&coarse_a), (coarse_a.hashes.len(), coarse_a.hashes.len(), coarse_a.hashes.len()));
+        assert_eq!(sketch_a.jaccard_unch(&coarse_b), coarse_a.jaccard_unch(&coarse_b));
+        assert_eq!(coarse_a.containment_unch(&sketch_b), coarse_a.containment_unch(&coarse_b));
+
+        // one row per query, one column per reference
+        let queries = [sketch_a.clone(), prefix.clone()];
+        let references = [sketch_b.clone(), prefix.clone(), sketch_a.clone()];
+        let matrix = sketch_matrix(&queries, &references, Sketch::containment_unch);
+        assert_eq!(matrix.len(), 6);
+        for (i, q) in queries.iter().enumerate() {
+            for (j, r) in references.iter().enumerate() {
+                assert_eq!(matrix[i * references.len() + j], q.containment_unch(r));
+            }
+        }
+        let a_in_b = shared as f64 / set_a.len() as f64;
+        assert_eq!(matrix, [a_in_b, a_in_b, 1.0, 1.0, 1.0, 1.0]);
+        assert!(sketch_matrix(&[], &references, Sketch::ani_unch).is_empty());
+    }
+
+    #[test]
+    fn canonical_tables() {
+        let fixed: [&[u32]; 5] = [&ONEMERTABLE, &TWOMERTABLE, &THREEMERTABLE, &FOURMERTABLE, &FIVEMERTABLE];
+        // gen_canonical_table numbers the columns in another order (from k = 2 on), so k <= 5 must keep using the fixed
+        // tables for find_kmer_distributions to have the column order of find_nMer_distributions
+        for (k, tbl) in (1..=5).zip(fixed) {
+            assert_eq!(canonical_table(k), tbl, "k = {}", k);
+        }
+        for k in 1..=MAX_CANONICAL_K {
+            let tbl = canonical_table(k);
+            let mut codes_per_column = vec![0; num_canonical_kmers(k)];
+            for (code, &column) in tbl.iter().enumerate() {
+                assert_eq!(column, tbl[rev_comp_2bit(code as u64, k) as usize]);
+                codes_per_column[column as usize] += 1;
+            }
+            // a kmer and its reverse compliment share a column, palindromes (only for even k) have one to themselves
+            let palindromes = codes_per_column.iter().filter(|&&n| n == 1).count();
+            assert_eq!(palindromes, if k % 2 == 0 { 1 << k } else { 0 }, "k = {}", k);
+            assert!(codes_per_column.iter().all(|&n| n == 1 || n == 2), "k = {}", k);
+        }
+    }
+
+    #[test]
+    fn kmer_distrs_match_nmer_counts() {
+        let mut rng = StdRng::seed_from_u64(13);
+        let ks = [5, 3, 1, 4, 2];
+        for len in [3, 5, 6, 100, 3000] {
+            let mut contig = random_contig(&mut rng, len);
+            for _ in 0..len / 20 {
+                let i = rng.gen_range(0..len);
+                contig[i] = b"RYSWKMBDHVryn"[rng.gen_range(0..13)];
+            }
+            for max_ambiguous in [None, Some(0), Some(1), Some(3)] {
+                for skip_masked in [false, true] {
+                    let opts = CountOptions { max_ambiguous, skip_masked };
+                    let counts = contig_2_frac_nmer_counts_bytes(&contig, opts);
+                    let expected: [&[f64]; 5] = [&counts.0, &counts.2, &counts.4, &counts.1, &counts.3];
+                    let (distrs, invalid_count) = contig_2_kmer_distrs_bytes(&contig, &ks, opts);
+                    assert_eq!(invalid_count, counts.5, "{} {:?} {}", len, max_ambiguous, skip_masked);
+                    for (distr, expected) in distrs.iter().zip(expected) {
+                        let sum = expected.iter().sum::<f64>();
+                        for (x, y) in distr.iter().zip(expected) {
+                            assert!((*x as f64 - y / sum).abs() < 1e-6 || (x.is_nan() && sum == 0.0));
                         }
-                        self.current_sequence += 1;
-                        i+= 1;
                     }
-                    return;
-                } else if bytes_read == None {
-                    dbg!("possible error from rust in skip");
-                    return;
                 }
-    
-                if self.buffer.starts_with(b">") {
+            }
+        }
+    }
 
-                    self.buffer.clear();
-                } else {
-                    // dbg!("ee");
-                    if !self.sequence.is_empty() {
-                        let result = tokenize_dna_fast(&self.sequence[..],self.min_size);
-                        self.sequence.clear();
-                        self.sequence.extend_from_slice(&self.buffer);
-                        self.buffer.clear();
-                        if result == false {
-                            continue;
+    #[test]
+    fn spaced_seeds() {
+        for mask in ["", "0", "000", "1121", "11 1", "1O1", &"1".repeat(13), &format!("1{}1", "0".repeat(31))] {
+            assert!(SpacedSeed::parse(mask).is_none(), "{:?}", mask);
+        }
+        for mask in ["1", "11011", &"1".repeat(12), &format!("1{}1", "0".repeat(30))] {
+            assert!(SpacedSeed::parse(mask).is_some(), "{:?}", mask);
+        }
+
+        let mut rng = StdRng::seed_from_u64(14);
+        let contigs = [random_contig(&mut rng, 3), random_contig(&mut rng, 7), random_contig(&mut rng, 2000)];
+        for contig in &contigs {
+            // a contiguous seed is the k mer block
+            let opts = CountOptions::default();
+            let five = SpacedSeed::parse("11111").unwrap();
+            assert_close(&seed_distr(contig, &five, opts), &contig_2_kmer_distrs_bytes(contig, &[5], opts).0[0].iter().map(|&x| x as f64).collect::<Vec<_>>());
+            let opts = CountOptions { skip_masked: true, ..opts };
+            assert_close(&seed_distr(contig, &five, opts), &contig_2_kmer_distrs_bytes(contig, &[5], opts).0[0].iter().map(|&x| x as f64).collect::<Vec<_>>());
+
+            for mask in ["11011", "110100101", "1001", "0110", "1101", "011"] {
+                let seed = SpacedSeed::parse(mask).unwrap();
+                let distr = seed_distr(contig, &seed, opts);
+                // strand independent
+                assert_close(&seed_distr(&revcomp(contig), &seed, opts), &distr.iter().map(|&x| x as f64).collect::<Vec<_>>());
+                // the care positions of every window of both strands, as strings
+                let tbl = canonical_table(seed.weight());
+                let mut counts = vec![0.0f64; num_canonical_kmers(seed.weight())];
+                for strand in [contig.clone(), revcomp(contig)] {
+                    for window in strand.windows(seed.span) {
+                        let kmer = seed.care.iter().map(|&i| opts.mask(window[i])).collect::<Vec<u8>>();
+                        if let Some(code) = kmer.iter().try_fold(0usize, |code, &b| Some((code << 2) | base2number(b)? as usize)) {
+                            counts[tbl[code] as usize] += 1.0;
                         }
-                        self.current_sequence += 1;
-                        i += 1;
                     }
-                    self.sequence.extend_from_slice(&self.buffer);
-                    self.buffer.clear();
                 }
+                let sum = counts.iter().sum::<f64>();
+                if sum > 0.0 {
+                    assert_close(&distr, &counts.iter().map(|x| x / sum).collect::<Vec<_>>());
+                }
+            }
         }
-        
     }
-}
-
-impl Iterator for FastaIterator {
-    type Item = Vec<i32>;
 
-    fn next(&mut self) -> Option<Self::Item> {
+    #[test]
+    fn groups_pool_counts() {
+        let norms = DEFAULT_NORMS.to_vec();
+        let opts = CountOptions::default();
+        let (long, short): (&[u8], &[u8]) = (b"AAAAAA", b"CG");
+        let pooled = group_distrs(&[&vec![long, short], &vec![short]], &norms, opts);
+        let a = ONEMERTABLE[base2number(b'A').unwrap() as usize] as usize;
+        let c = ONEMERTABLE[base2number(b'C').unwrap() as usize] as usize;
+        // 6 A/T and 2 C/G bases, where the mean of the two profiles would be [0.5, 0.5]
+        assert_eq!((pooled[0].0.5[a], pooled[0].0.5[c]), (0.75, 0.25));
+        assert_eq!((pooled[1].0.5[a], pooled[1].0.5[c]), (0.0, 1.0));
+        // 5 AA and 1 CG
+        assert_eq!(pooled[0].0.4[TWOMERTABLE[kmer_to_16_bit_ray::<2>(b"AA").unwrap() as usize] as usize], 5.0 / 6.0);
+        assert_eq!(pooled[0].0.4[TWOMERTABLE[kmer_to_16_bit_ray::<2>(b"CG").unwrap() as usize] as usize], 1.0 / 6.0);
+
+        // every block is the normalized sum of the members' counts
+        let mut rng = StdRng::seed_from_u64(15);
+        let contigs = [random_contig(&mut rng, 50), random_contig(&mut rng, 3000), random_contig(&mut rng, 700)];
+        let group = contigs.iter().map(|c| &c[..]).collect::<Vec<_>>();
+        let (nmers, rymers) = &group_distrs(&[&group], &norms, opts)[0];
+        let mut summed = empty_group_counts();
+        for ctg in &contigs {
+            summed = merge_group_counts(summed, (contig_2_frac_nmer_counts_bytes(ctg, opts), contig_2_frac_rymer_counts_bytes(ctg, opts)));
+        }
+        assert_eq!(*nmers, nmer_distrs_from_counts(summed.0, &norms[..5]));
+        assert_eq!(*rymers, rymer_distrs_from_counts(summed.1, &norms[5..]));
+        let mean_5mers = contigs.iter()
+                                .map(|ctg| nmer_distrs_from_counts(contig_2_frac_nmer_counts_bytes(ctg, opts), &norms[..5]).0)
+                                .fold([0.0f32; 512], |mut acc, x| { acc.iter_mut().zip(x).for_each(|(a, x)| *a += x / 3.0); acc });
+        assert!(nmers.0.iter().zip(mean_5mers).any(|(x, y)| (x - y).abs() > 1e-3));
+    }
 
-        loop {
-            let bytes_read = self.reader.read_until(b'>', &mut self.buffer).ok()?;
-
-            if bytes_read == 0 {
-                // dbg!("a");
-                if !self.sequence.is_empty() {
-                    // dbg!("b");
-                    let res = tokenize_dna(&self.sequence,self.min_size);
-                    self.sequence.clear();
-                    if res == None {
-                        continue;
+    #[test]
+    fn stranded_and_masked_counts() {
+        let tables: [&[u32]; NUM_FEATURE_BLOCKS] = [&FIVEMERTABLE, &FOURMERTABLE, &THREEMERTABLE, &TWOMERTABLE, &ONEMERTABLE,
+                                                    &RY10TBL, &RY9TBL, &RY8TBL, &RY7TBL, &RY6TBL];
+        let ks = [5, 4, 3, 2, 1, 10, 9, 8, 7, 6];
+        let mut rng = StdRng::seed_from_u64(16);
+        for len in [1, 4, 5, 9, 10, 11, 600] {
+            let contig = random_contig(&mut rng, len);
+            let (stranded, invalid, invalid_ry) = stranded_counts(contig.iter().copied());
+            let canonical = block_counts(contig.iter().copied(), false);
+            assert_eq!((invalid, invalid_ry), (canonical.1, canonical.2));
+            for (i, (counts, tbl)) in stranded.iter().zip(tables).enumerate() {
+                // folding kmers with their reverse compliments gives the canonical counts
+                let mut folded = vec![0u32; FEATURE_BLOCK_SIZES[i]];
+                for (code, &n) in counts.iter().enumerate() {
+                    folded[tbl[code] as usize] += n;
+                }
+                assert_eq!(folded, canonical.0[i], "len {} block {}", len, i);
+
+                // and the index is the kmer's code; RY windows are the prefixes of the 10 base windows
+                let (k, ry) = (ks[i], i >= 5);
+                let mut expected = vec![0u32; counts.len()];
+                let windows = if ry { contig.windows(10).map(|w| &w[..k]).collect::<Vec<_>>() } else { contig.windows(k).collect() };
+                for w in windows {
+                    let code = if ry { old_kmer_code(w, base2number_ry, 1) } else { old_kmer_code(w, base2number, 2) };
+                    if let Some(code) = code {
+                        expected[code as usize] += 1;
                     }
-                    self.current_sequence += 1;
-                    return res
                 }
-                return None;
+                assert_eq!(*counts, expected, "len {} block {}", len, i);
             }
 
-            if self.buffer.starts_with(b">") {
-                // dbg!("c");
-
-                self.buffer.clear();
-            } else {
-                // dbg!("ee");
-                if !self.sequence.is_empty() {
-                    // dbg!("d");
-                    let cut = if self.sequence[self.sequence.len()-1] == b'>' {
-                        self.sequence.len() -1
-                    }  else {
-                        self.sequence.len()
-                    };
-                    let result = tokenize_dna(&self.sequence[..cut],self.min_size);
-                    self.sequence.clear();
-                    // self.buffer.clear();
-                    self.sequence.extend_from_slice(&self.buffer);
-                    self.buffer.clear();
-                    if result == None {
-                        continue;
-                    }
-                    self.current_sequence += 1;
-                    return result;
+            for stranded in [false, true] {
+                let all = block_counts(contig.iter().copied(), stranded);
+                let (unmasked, masked, num_masked) = masked_block_counts(contig.iter().copied(), stranded);
+                assert_eq!(num_masked, contig.iter().filter(|b| b.is_ascii_lowercase()).count());
+                // every window goes to exactly one of the two profiles
+                for i in 0..NUM_FEATURE_BLOCKS {
+                    assert_eq!(unmasked.0[i].iter().zip(&masked.0[i]).map(|(a, b)| a + b).collect::<Vec<_>>(), all.0[i]);
                 }
-                self.sequence.extend_from_slice(&self.buffer);
-                self.buffer.clear();
+                assert_eq!((unmasked.1 + masked.1, unmasked.2 + masked.2), (all.1, all.2));
+                // the unmasked profile counts the windows without a lowercase base
+                let dashed = contig.iter().map(|&b| if b.is_ascii_lowercase() { b'-' } else { b }).collect::<Vec<_>>();
+                assert_eq!(unmasked.0, block_counts(dashed.iter().copied(), stranded).0);
+                // and an all lowercase contig only has masked windows
+                let (unmasked, masked, _) = masked_block_counts(contig.iter().map(|b| b.to_ascii_lowercase()), stranded);
+                assert!(unmasked.0.iter().flatten().all(|&n| n == 0) && unmasked.1 == 0 && unmasked.2 == 0);
+                assert_eq!(masked, all);
             }
         }
     }
-}
-
-fn tokenize_dna_fast(fasta_entry: &[u8], min: usize) -> bool {
-
-    // Skip the first line (sequence name and other information)
-    let mut lines = fasta_entry.split(|&b| b == b'\n');
-    lines.next();
-
-    return lines.map(|x| x.iter().filter(|&y| *y != b'>').count()).sum::<usize>() >= min;
-}
-
-fn tokenize_dna(fasta_entry: &[u8], min: usize) -> Option<Vec<i32>> {
-    let mut sequence = Vec::new();
-
-    // Skip the first line (sequence name and other information)
-    let mut lines = fasta_entry.split(|&b| b == b'\n');
-    lines.next();
 
-    // Concatenate the remaining lines (DNA sequence)
-    for line in lines {
-        sequence.extend_from_slice(line);
+    #[test]
+    fn windows_by_hand() {
+        assert_eq!(window_starts(5, 10, 1), []);
+        assert_eq!(window_starts(10, 10, 3), [(0, 10)]);
+        assert_eq!(window_starts(10, 4, 3), [(0, 4), (3, 7), (6, 10)]);
+        // no partial window at the end: a start of 9 doesn't fit
+        assert_eq!(window_starts(12, 4, 3), [(0, 4), (3, 7), (6, 10)]);
+
+        let mut rng = StdRng::seed_from_u64(17);
+        let (short, long) = (random_contig(&mut rng, 7), random_contig(&mut rng, 20));
+        let norms = DEFAULT_NORMS.to_vec();
+        let opts = CountOptions::default();
+        let profiles = window_profiles(&[&short, &long, &short], 8, 5, &norms, opts);
+        assert_eq!(profiles.len(), 3);
+        assert!(profiles[0].0.is_empty() && profiles[0].1.is_empty());
+        assert!(profiles[2].0.is_empty() && profiles[2].1.is_empty());
+        let (rows, coords) = &profiles[1];
+        assert_eq!(*coords, [(0, 8), (5, 13), (10, 18)]);
+        assert_eq!(rows.len(), 3 * WINDOW_PROFILE_SIZE);
+        for (row, &(start, end)) in rows.chunks(WINDOW_PROFILE_SIZE).zip(coords) {
+            let expected = window_profile(&long[start..end], &norms, opts);
+            assert!(row.iter().zip(&expected).all(|(x, y)| x == y || (x.is_nan() && y.is_nan())));
+        }
     }
 
-    if sequence.len() < min {
-        return None;
+    #[test]
+    fn contig_stats_by_hand() {
+        // A5 T1 C1 G2, then N N R: 3 ambiguous; 4 invalid 5 base windows (starts 4..7) and all 3 RY 10 base windows
+        let stats = contig_stats(b"AAAAAGGCNnRt", 3);
+        let expected = [3.0 / 9.0, 1.0 / 3.0, 4.0 / 6.0, 2.0 / 12.0, 3.0 / 12.0, 5.0, 1.0, 5.0 / 12.0, 4.0, 3.0];
+        assert_close(&stats, &expected);
+        // runs of exactly homopolymer_len count, N runs don't
+        let stats = contig_stats(b"CCCNNNNNTTGGG", 3);
+        assert_eq!(stats[5..8], [3.0, 2.0, 6.0 / 13.0]);
+        assert_eq!(contig_stats(b"", 5), [0.0; NUM_CONTIG_STATS]);
+
+        // the invalid window counts are the ones of find_nMer_counts
+        let mut rng = StdRng::seed_from_u64(18);
+        for len in [0, 4, 5, 9, 10, 300] {
+            let contig = random_contig(&mut rng, len);
+            let (_, invalid, invalid_ry) = block_counts(contig.iter().copied(), false);
+            assert_eq!(contig_stats(&contig, 5)[8..], [invalid as f32, invalid_ry as f32]);
+        }
     }
 
-    // dbg!(String::from_utf8(sequence.clone()));
-
-    // Convert the DNA sequence to labeled k-mers
-    let mut result = Vec::new();
-    for chunk in sequence.windows(5) {
-        result.push(kmer_to_16_bit_ray::<5>(chunk).unwrap_or_else(|| 1024) as i32);
+    #[test]
+    fn skew_curve_by_hand() {
+        // GGGC: gc skew 1/2, no A/T; AATT: 0, 0; CCGA: gc skew -1/3, at skew 1; the last 2 bases aren't a full window
+        let curve = skew_curve(b"GGGCAATTCCGAGG", 4);
+        let expected = [[0.5, 0.5, 0.0, 0.0], [0.0, 0.5, 0.0, 0.0], [-1.0 / 3.0, 0.5 - 1.0 / 3.0, 1.0, 1.0]];
+        assert_eq!(curve.len(), expected.len());
+        for (row, expected) in curve.iter().zip(expected) {
+            assert_close(row, &expected);
+        }
+        // N doesn't count as a base
+        assert_close(&skew_curve(b"GNNN", 4)[0], &[1.0, 1.0, 0.0, 0.0]);
+        assert!(skew_curve(b"GGG", 4).is_empty());
     }
-    let window_size = 5;
-    for (idx, i) in (window_size..=sequence.len()).rev().enumerate() {
-        let window = &sequence[i - window_size..i];
-        result.push(kmer_to_16_bit_ray_revcomp::<5>(window).unwrap_or_else( || 1024) as i32);
-      }
-
-    return Some(result);
-}
 
-use numpy::{ PyArray2};
-use pyo3::prelude::*;
-use numpy::npyffi::types::NPY_ORDER;
-#[pyclass]
-struct PyFastaIterator {
-    inner: FastaIterator,
-}
-
-#[pymethods]
-impl PyFastaIterator {
-    #[new]
-    fn new(file_path: &str, min_size: usize) -> Self {
-        PyFastaIterator {
-            inner: FastaIterator::new(file_path, min_size),
+    // canonical k mer -> count, one window at a time
+    fn naive_long_kmers(seqs: &[Vec<u8>], k: usize) -> HashMap<u64, u32> {
+        let mut counts = HashMap::new();
+        for window in seqs.iter().flat_map(|seq| seq.windows(k)) {
+            if let Some(code) = window.iter().try_fold(0u64, |code, &b| Some(code << 2 | base2number(b)? as u64)) {
+                *counts.entry(code.min(rev_comp_2bit(code, k))).or_insert(0) += 1;
+            }
         }
+        counts
     }
 
-    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
-        slf
+    #[test]
+    fn long_kmers_across_chunks() {
+        let mut rng = StdRng::seed_from_u64(19);
+        let acgt = |rng: &mut StdRng, len: usize| (0..len).map(|_| b"ACGT"[rng.gen_range(0..4)]).collect::<Vec<u8>>();
+        for k in [1, 2, 21, 31] {
+            for chunk in [1, 7, 100] {
+                // exactly one chunk of k mers, one k mer into the second chunk, and many chunks with Ns
+                let seqs = [acgt(&mut rng, chunk + k - 1), acgt(&mut rng, chunk + k), random_contig(&mut rng, 20 * chunk + 13)];
+                for seq in &seqs {
+                    let seq = std::slice::from_ref(seq);
+                    assert_eq!(count_long_kmers(seq, k, chunk), naive_long_kmers(seq, k), "k {} chunk {}", k, chunk);
+                    assert_eq!(count_long_kmers(seq, k, chunk), count_long_kmers(seq, k, 1 << 40));
+                }
+                let counts = count_long_kmers(&seqs[..2], k, chunk);
+                assert_eq!(counts.values().map(|&c| c as usize).sum::<usize>(), 2 * chunk + 1);
+            }
+        }
+        // the chunk size KmerSpectrum uses: a k mer starting at the last base of the first chunk is counted once
+        let seq = [acgt(&mut rng, LONG_KMER_CHUNK + 31)];
+        assert_eq!(count_long_kmers(&seq, 31, LONG_KMER_CHUNK).values().sum::<u32>(), LONG_KMER_CHUNK as u32 + 1);
+        // shorter than k: nothing
+        assert!(count_long_kmers(&[b"ACGT".to_vec()], 5, LONG_KMER_CHUNK).is_empty());
     }
 
-    fn __next__(mut slf: PyRefMut<Self>) -> Option<Py<PyArray2<i32>>> {
-        slf.inner.next().map(|tokens| {
-            let gil = Python::acquire_gil();
-            let py = gil.python();
-            let len = tokens.len();
-            // dbg!(&tokens);
-            let arr = PyArray1::from_vec(py, tokens).reshape_with_order((2, len / 2), NPY_ORDER::NPY_CORDER).unwrap();
-            arr.to_owned()
-        })
-    }
-    fn skip_to(&mut self, n: usize) {
-        self.inner.skip_to(n);
-    }
-    fn size(&self) -> usize {
-        return self.inner.count;
+    #[test]
+    fn spectrum_reads_batches() {
+        let mut rng = StdRng::seed_from_u64(20);
+        let reads = (0..50).map(|i| random_contig(&mut rng, 20 + i * 7)).collect::<Vec<_>>();
+        let mut fastq = Vec::new();
+        for (i, read) in reads.iter().enumerate() {
+            let qual = (0..read.len()).map(|_| rng.gen_range(b'#'..=b'J')).collect::<Vec<u8>>();
+            fastq.extend_from_slice(format!("@r{}\n", i).as_bytes());
+            fastq.extend_from_slice(read);
+            fastq.extend_from_slice(b"\n+\n");
+            fastq.extend_from_slice(&qual);
+            fastq.push(b'\n');
+        }
+        let fasta = reads.iter().enumerate().flat_map(|(i, read)| [format!(">r{}\n", i).into_bytes(), read.clone(), b"\n".to_vec()]).flatten().collect::<Vec<u8>>();
+        for batch_size in [1, 100, usize::MAX] {
+            for min_len in [0, 200] {
+                let expected = |text: &[u8], min_quality| {
+                    let seqs = parse_fastq_or_fasta(text, min_len, min_quality);
+                    count_long_kmers(&seqs, 11, LONG_KMER_CHUNK)
+                };
+                assert_eq!(count_reader_kmers(&mut &fasta[..], 11, min_len, None, batch_size).unwrap(), expected(&fasta, None));
+                assert_eq!(count_reader_kmers(&mut &fastq[..], 11, min_len, None, batch_size).unwrap(), expected(&fastq, None));
+                let masked = count_reader_kmers(&mut &fastq[..], 11, min_len, Some(20), batch_size).unwrap();
+                assert_eq!(masked, expected(&fastq, Some(20)));
+                assert!(masked.values().sum::<u32>() < expected(&fastq, None).values().sum::<u32>());
+            }
+        }
     }
 
+    fn parse_fastq_or_fasta(text: &[u8], min_len: usize, min_quality: Option<u8>) -> Vec<Vec<u8>> {
+        let records = if is_fastq(text) { parse_fastq(text, min_len, min_quality).unwrap() } else { parse_contigs(text, min_len).unwrap() };
+        records.into_iter().map(|(_, seq)| seq).collect()
+    }
 }
-
-